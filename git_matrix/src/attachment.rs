@@ -0,0 +1,105 @@
+//! Packs encrypted before uploading, in the format of Matrix' encrypted attachments
+//!
+//! Each pack gets a random AES-256-CTR key and IV, the SHA-256 hash of the
+//! ciphertext is checked before decrypting.
+
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::symm::{decrypt as aes_decrypt, encrypt as aes_encrypt, Cipher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::Error;
+
+/// Key, IV and hash of an encrypted pack, as in `m.file`'s `file` without `url`
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptedFile {
+    pub key: JsonWebKey,
+    /// Unpadded base64 of the IV
+    pub iv: String,
+    /// Unpadded base64 of the ciphertext's hashes, by algorithm
+    pub hashes: HashMap<String, String>,
+    /// Always `v2`
+    pub v: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct JsonWebKey {
+    pub kty: String,
+    pub key_ops: Vec<String>,
+    pub alg: String,
+    /// Unpadded URL-safe base64 of the key
+    pub k: String,
+    pub ext: bool,
+}
+
+pub fn encrypt(data: &[u8]) -> Result<(Vec<u8>, EncryptedFile), Error> {
+    let mut key = [0; 32];
+    rand_bytes(&mut key)?;
+    // the upper half of the IV is random, the lower half is the block counter
+    let mut iv = [0; 16];
+    rand_bytes(&mut iv[..8])?;
+
+    let ciphertext = aes_encrypt(Cipher::aes_256_ctr(), &key, Some(&iv), data)?;
+
+    let mut hashes = HashMap::new();
+    hashes.insert("sha256".to_owned(), hash(&ciphertext));
+
+    Ok((
+        ciphertext,
+        EncryptedFile {
+            key: JsonWebKey {
+                kty: "oct".to_owned(),
+                key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+                alg: "A256CTR".to_owned(),
+                k: encode(&key).replace('+', "-").replace('/', "_"),
+                ext: true,
+            },
+            iv: encode(&iv),
+            hashes,
+            v: "v2".to_owned(),
+        },
+    ))
+}
+
+/// Decrypt the pack, after checking its hash
+pub fn decrypt(ciphertext: &[u8], file: &EncryptedFile) -> Result<Vec<u8>, Error> {
+    match file.hashes.get("sha256") {
+        Some(hash) if decode(hash)? == sha256(ciphertext) => (),
+        _ => {
+            return Err(Error {
+                message: "The hash of the encrypted pack doesn't match".to_owned(),
+            })
+        }
+    }
+
+    let key = decode(&file.key.k.replace('-', "+").replace('_', "/"))?;
+    let iv = decode(&file.iv)?;
+    if file.key.alg != "A256CTR" || key.len() != 32 || iv.len() != 16 {
+        return Err(Error {
+            message: "Unsupported pack encryption".to_owned(),
+        });
+    }
+
+    Ok(aes_decrypt(
+        Cipher::aes_256_ctr(),
+        &key,
+        Some(&iv),
+        ciphertext,
+    )?)
+}
+
+/// Unpadded base64 of the SHA-256 hash of the data
+pub fn hash(data: &[u8]) -> String {
+    encode(&sha256(data))
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_block(data).trim_end_matches('=').to_owned()
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, Error> {
+    let padding = "=".repeat((4 - data.len() % 4) % 4);
+    Ok(base64::decode_block(&format!("{}{}", data, padding))?)
+}
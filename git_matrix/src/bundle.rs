@@ -0,0 +1,276 @@
+//! Repositories as git bundles, for moving them between rooms or offline
+//!
+//! Exported bundles are in the v2 format, with all refs of the room and
+//! without prerequisites, so that `git clone` can read them. Imported bundles
+//! must not have prerequisites either, their pack becomes the room's first pack.
+
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::git::Pack;
+use crate::user_refs;
+use crate::GitMatrix;
+
+const V2_SIGNATURE: &str = "# v2 git bundle\n";
+const V3_SIGNATURE: &str = "# v3 git bundle\n";
+
+pub struct Bundle {
+    /// Refs by name, without `HEAD`
+    pub refs: Vec<(String, String)>,
+    /// Branch `HEAD` points to, if the bundle has a `HEAD`
+    pub head_branch: Option<String>,
+    pub pack: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn read(data: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error {
+            message: "Not a git bundle".to_owned(),
+        };
+
+        let mut rest = if data.starts_with(V2_SIGNATURE.as_bytes()) {
+            &data[V2_SIGNATURE.len()..]
+        } else if data.starts_with(V3_SIGNATURE.as_bytes()) {
+            &data[V3_SIGNATURE.len()..]
+        } else {
+            return Err(invalid());
+        };
+
+        let mut refs = Vec::new();
+        let mut head = None;
+        loop {
+            let end = rest
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .ok_or_else(invalid)?;
+            let line = String::from_utf8_lossy(&rest[..end]).into_owned();
+            rest = &rest[end + 1..];
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some(capability) = line.strip_prefix('@') {
+                if capability != "object-format=sha1" {
+                    return Err(Error {
+                        message: format!("Bundles with {} are not supported", capability),
+                    });
+                }
+            } else if line.starts_with('-') {
+                return Err(Error {
+                    message:
+                        "Bundles with prerequisites can't be imported, create the bundle with --all"
+                            .to_owned(),
+                });
+            } else {
+                let (sha, name) = line.split_once(' ').ok_or_else(invalid)?;
+                if name == "HEAD" {
+                    head = Some(sha.to_owned());
+                } else {
+                    refs.push((name.to_owned(), sha.to_owned()));
+                }
+            }
+        }
+
+        // HEAD is stored as commit, take the branch it is at, as git clone does
+        let head_branch = head.and_then(|head| {
+            refs.iter()
+                .find(|(name, sha)| *sha == head && name.starts_with("refs/heads/"))
+                .map(|(name, _)| name.trim_start_matches("refs/heads/").to_owned())
+        });
+
+        Ok(Self {
+            refs,
+            head_branch,
+            pack: rest.to_vec(),
+        })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.as_bytes().to_vec();
+        let head = self.head_branch.as_ref().and_then(|branch| {
+            let name = format!("refs/heads/{}", branch);
+            self.refs.iter().find(|(ref_name, _)| *ref_name == name)
+        });
+        if let Some((_, sha)) = head {
+            data.extend(format!("{} HEAD\n", sha).as_bytes());
+        }
+        for (name, sha) in &self.refs {
+            data.extend(format!("{} {}\n", sha, name).as_bytes());
+        }
+        data.push(b'\n');
+        data.extend(&self.pack);
+
+        data
+    }
+}
+
+impl GitMatrix {
+    /// Bundle of all refs of the room, their objects are fetched first
+    pub async fn export_bundle(&self) -> Result<Bundle, Error> {
+        let mut refs: Vec<(String, String)> = self.refs().await?.into_iter().collect();
+        refs.sort();
+
+        let mut shas: Vec<String> = refs.iter().map(|(_, sha)| sha.clone()).collect();
+        shas.sort();
+        shas.dedup();
+        self.fetch_commits(&shas).await?;
+
+        Ok(Bundle {
+            refs,
+            head_branch: self.repo().and_then(|repo| repo.default_branch.clone()),
+            pack: self.git.pack_commits(&shas)?.content,
+        })
+    }
+
+    /// Send the bundle's pack and set all of its refs
+    ///
+    /// The pack is sent as checkpoint, as it has all objects of the refs.
+    /// Personal refs of other users are left out, they are returned.
+    pub async fn import_bundle(&self, bundle: Bundle) -> Result<Vec<String>, Error> {
+        let user_id = self.matrix.user_id().map(|user_id| user_id.to_string());
+        let (refs, skipped): (HashMap<String, String>, HashMap<String, String>) =
+            bundle.refs.into_iter().partition(|(ref_name, _)| {
+                user_refs::owner(ref_name).is_none() || user_refs::owner(ref_name) == user_id
+            });
+        for ref_name in refs.keys() {
+            self.check_protection(ref_name).await?;
+        }
+
+        let mut tips: Vec<String> = refs.values().cloned().collect();
+        tips.sort();
+        tips.dedup();
+        let entry = self
+            .send_pack(
+                Pack {
+                    content: bundle.pack,
+                },
+                tips,
+                true,
+            )
+            .await?;
+
+        for (ref_name, sha) in refs {
+            self.send_ref(&ref_name, sha).await?;
+        }
+
+        self.update_pack_manifest(entry, false).await?;
+
+        Ok(skipped.into_keys().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "1111111111111111111111111111111111111111";
+    const TOPIC: &str = "2222222222222222222222222222222222222222";
+
+    fn bundle(header: &str) -> Vec<u8> {
+        let mut data = header.as_bytes().to_vec();
+        data.extend(b"PACK...");
+        data
+    }
+
+    fn error(data: &[u8]) -> String {
+        match Bundle::read(data) {
+            Ok(_) => panic!("the bundle was read"),
+            Err(error) => error.message,
+        }
+    }
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let written = Bundle {
+            refs: vec![
+                ("refs/heads/main".to_owned(), MAIN.to_owned()),
+                ("refs/heads/topic".to_owned(), TOPIC.to_owned()),
+            ],
+            head_branch: Some("main".to_owned()),
+            pack: b"PACK...".to_vec(),
+        };
+
+        let data = written.write();
+        assert!(data.starts_with(
+            format!("# v2 git bundle\n{} HEAD\n{} refs/heads/main\n", MAIN, MAIN).as_bytes()
+        ));
+
+        let read = Bundle::read(&data).unwrap();
+        assert_eq!(read.refs, written.refs);
+        assert_eq!(read.head_branch, written.head_branch);
+        assert_eq!(read.pack, written.pack);
+    }
+
+    #[test]
+    fn head_is_resolved_to_its_branch() {
+        let data = bundle(&format!(
+            "# v2 git bundle\n{} HEAD\n{} refs/tags/v1\n{} refs/heads/main\n{} refs/heads/topic\n\n",
+            TOPIC, TOPIC, MAIN, TOPIC
+        ));
+
+        let read = Bundle::read(&data).unwrap();
+        assert_eq!(read.head_branch, Some("topic".to_owned()));
+        assert_eq!(read.refs.len(), 3);
+        assert!(read.refs.iter().all(|(name, _)| name != "HEAD"));
+    }
+
+    #[test]
+    fn bundles_without_head_have_no_head_branch() {
+        let data = bundle(&format!("# v2 git bundle\n{} refs/heads/main\n\n", MAIN));
+
+        let read = Bundle::read(&data).unwrap();
+        assert_eq!(read.head_branch, None);
+        assert!(!read.write().windows(5).any(|window| window == b" HEAD"));
+    }
+
+    #[test]
+    fn v3_with_sha1_object_format_is_read() {
+        let data = bundle(&format!(
+            "# v3 git bundle\n@object-format=sha1\n{} HEAD\n{} refs/heads/main\n\n",
+            MAIN, MAIN
+        ));
+
+        let read = Bundle::read(&data).unwrap();
+        assert_eq!(
+            read.refs,
+            vec![("refs/heads/main".to_owned(), MAIN.to_owned())]
+        );
+        assert_eq!(read.head_branch, Some("main".to_owned()));
+        assert_eq!(read.pack, b"PACK...".to_vec());
+    }
+
+    #[test]
+    fn unsupported_capabilities_are_rejected() {
+        for capability in &["object-format=sha256", "filter=blob:none"] {
+            let data = bundle(&format!(
+                "# v3 git bundle\n@{}\n{} refs/heads/main\n\n",
+                capability, MAIN
+            ));
+
+            assert_eq!(
+                error(&data),
+                format!("Bundles with {} are not supported", capability)
+            );
+        }
+    }
+
+    #[test]
+    fn prerequisites_are_rejected() {
+        let data = bundle(&format!(
+            "# v2 git bundle\n-{} base\n{} refs/heads/main\n\n",
+            TOPIC, MAIN
+        ));
+
+        assert!(error(&data).starts_with("Bundles with prerequisites can't be imported"));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert_eq!(error(b"PACK..."), "Not a git bundle");
+        assert_eq!(error(b"# v4 git bundle\n\n"), "Not a git bundle");
+        assert_eq!(
+            error(format!("# v2 git bundle\n{} refs/heads/main", MAIN).as_bytes()),
+            "Not a git bundle"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use ruma_client::identifiers::UserId;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::packs::PACKS_EVENT_TYPE;
+use crate::protection::{PowerLevels, POWER_LEVELS_EVENT_TYPE};
+use crate::user_refs::USER_REFS_EVENT_TYPE;
+use crate::GitMatrix;
+
+/// Event types writers need to be able to send
+const WRITE_EVENT_TYPES: [&str; 4] = [
+    "org.gitmatrix.refs",
+    "org.gitmatrix.pack",
+    PACKS_EVENT_TYPE,
+    USER_REFS_EVENT_TYPE,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Reader,
+    Writer,
+    Maintainer,
+}
+
+impl Role {
+    pub fn power_level(self) -> i64 {
+        match self {
+            Role::Reader => 0,
+            Role::Writer => 50,
+            Role::Maintainer => 100,
+        }
+    }
+
+    fn from_power_level(power_level: i64) -> Self {
+        if power_level >= Role::Maintainer.power_level() {
+            Role::Maintainer
+        } else if power_level >= Role::Writer.power_level() {
+            Role::Writer
+        } else {
+            Role::Reader
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "reader" => Ok(Role::Reader),
+            "writer" => Ok(Role::Writer),
+            "maintainer" => Ok(Role::Maintainer),
+            _ => Err(Error {
+                message: format!(
+                    "Unknown role {}, expected reader, writer or maintainer",
+                    role
+                ),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            Role::Reader => "reader",
+            Role::Writer => "writer",
+            Role::Maintainer => "maintainer",
+        };
+        write!(f, "{}", role)
+    }
+}
+
+pub struct Collaborator {
+    pub user_id: String,
+    /// `join` or `invite`
+    pub membership: String,
+    pub role: Role,
+}
+
+impl GitMatrix {
+    /// Joined and invited members of the room
+    pub async fn collaborators(&self) -> Result<Vec<Collaborator>, Error> {
+        let state = self.matrix.room_state().await?;
+
+        let mut power_levels = PowerLevels::default();
+        for event in state.iter() {
+            if event["type"] == POWER_LEVELS_EVENT_TYPE && event["state_key"] == "" {
+                power_levels = serde_json::from_value(event["content"].clone())?;
+            }
+        }
+
+        let mut collaborators = Vec::new();
+        for event in state.iter() {
+            let membership = event["content"]["membership"].as_str().unwrap_or_default();
+            if event["type"] != "m.room.member" || (membership != "join" && membership != "invite")
+            {
+                continue;
+            }
+            let user_id = event["state_key"].as_str().unwrap_or_default().to_owned();
+            collaborators.push(Collaborator {
+                role: Role::from_power_level(power_levels.user_level(&user_id)),
+                membership: membership.to_owned(),
+                user_id,
+            });
+        }
+        collaborators.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+        Ok(collaborators)
+    }
+
+    /// Set the user's role and invite them, unless they are joined or invited already
+    pub async fn add_collaborator(&self, user_id: &str, role: Role) -> Result<(), Error> {
+        self.set_role(user_id, role).await?;
+        if !self.is_member(user_id).await? {
+            self.matrix.invite(UserId::try_from(user_id)?).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the user's power level and kick them, or revoke their invite
+    ///
+    /// The power level is dropped first, so that it is reset for users that
+    /// already left the room as well.
+    pub async fn remove_collaborator(&self, user_id: &str) -> Result<(), Error> {
+        self.set_role(user_id, Role::Reader).await?;
+        if self.is_member(user_id).await? {
+            self.matrix.kick(UserId::try_from(user_id)?).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_role(&self, user_id: &str, role: Role) -> Result<(), Error> {
+        UserId::try_from(user_id)?;
+
+        let mut content = self
+            .matrix
+            .get_state_event(POWER_LEVELS_EVENT_TYPE, "")
+            .await?
+            .unwrap_or_else(|| json!({}));
+
+        for event_type in WRITE_EVENT_TYPES.iter() {
+            if content["events"][event_type].is_null() {
+                content["events"][event_type] = json!(Role::Writer.power_level());
+            }
+        }
+
+        if role == Role::Reader {
+            if let Some(users) = content["users"].as_object_mut() {
+                users.remove(user_id);
+            }
+        } else {
+            content["users"][user_id] = json!(role.power_level());
+        }
+
+        self.matrix
+            .send_state_event_for_key(POWER_LEVELS_EVENT_TYPE, "", content)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether the user is joined or invited
+    async fn is_member(&self, user_id: &str) -> Result<bool, Error> {
+        let membership = self
+            .matrix
+            .get_state_event("m.room.member", user_id)
+            .await?
+            .map(|content| content["membership"].clone());
+
+        Ok(membership == Some(json!("join")) || membership == Some(json!("invite")))
+    }
+}
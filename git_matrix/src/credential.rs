@@ -0,0 +1,294 @@
+use git2::Config;
+use ruma_client::identifiers::UserId;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::error::Error;
+use crate::matrix;
+
+/// A Matrix session, stored as
+///
+/// ```text
+/// [matrix "@user:example.org"]
+///     homeserver = https://matrix.example.org
+///     deviceId = ABCDEFGH
+/// [matrix "https://matrix.example.org"]
+///     user = @user:example.org
+/// ```
+///
+/// with the access token, and the refresh token if the homeserver issued one,
+/// kept by the credential helpers.
+#[derive(Clone)]
+pub struct Credentials {
+    pub homeserver_url: String,
+    pub user_id: String,
+    pub device_id: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+impl Credentials {
+    /// Session of the user, `None` if they aren't logged in
+    pub fn for_user(config: &Config, user_id: &str) -> Result<Option<Self>, Error> {
+        let homeserver_url = match config.get_string(&format!("matrix.{}.homeserver", user_id)) {
+            Ok(homeserver_url) => homeserver_url,
+            Err(_) => return Ok(None),
+        };
+        let device_id = config.get_string(&format!("matrix.{}.deviceId", user_id))?;
+        let (access_token, refresh_token) = match fill(&homeserver_url, user_id)? {
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            homeserver_url,
+            user_id: user_id.to_owned(),
+            device_id,
+            access_token,
+            refresh_token,
+        }))
+    }
+
+    /// Session of the user last logged in to the homeserver
+    pub fn for_homeserver(config: &Config, homeserver_url: &str) -> Result<Option<Self>, Error> {
+        let key = format!("matrix.{}.user", normalize_url(homeserver_url)?);
+        match config.get_string(&key) {
+            Ok(user_id) => Self::for_user(config, &user_id),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Session from `GIT_MATRIX_ACCESS_TOKEN`, for `GIT_MATRIX_HOMESERVER` if set
+    ///
+    /// `GIT_MATRIX_USER` and `GIT_MATRIX_DEVICE_ID` are looked up if not set.
+    pub async fn from_env(homeserver_url: &str) -> Result<Option<Self>, Error> {
+        let access_token = match std::env::var("GIT_MATRIX_ACCESS_TOKEN") {
+            Ok(access_token) => access_token,
+            Err(_) => return Ok(None),
+        };
+        let homeserver_url = match std::env::var("GIT_MATRIX_HOMESERVER") {
+            Ok(url) if normalize_url(&url)? != normalize_url(homeserver_url)? => return Ok(None),
+            Ok(url) => url,
+            Err(_) => homeserver_url.to_owned(),
+        };
+
+        let (user_id, device_id) = match (
+            std::env::var("GIT_MATRIX_USER"),
+            std::env::var("GIT_MATRIX_DEVICE_ID"),
+        ) {
+            (Ok(user_id), Ok(device_id)) => (user_id, device_id),
+            _ => {
+                let (user_id, device_id) = matrix::whoami(&homeserver_url, &access_token).await?;
+                (user_id, device_id.unwrap_or_default())
+            }
+        };
+
+        Ok(Some(Self {
+            homeserver_url,
+            user_id,
+            device_id,
+            access_token,
+            refresh_token: None,
+        }))
+    }
+
+    pub fn session(&self) -> Result<matrix::Session, Error> {
+        Ok(matrix::Session {
+            access_token: self.access_token.clone(),
+            user_id: UserId::try_from(&self.user_id[..])?,
+            device_id: self.device_id.clone(),
+        })
+    }
+
+    /// Exchange the refresh token for a new access token and store it with the
+    /// credential helpers
+    pub async fn refresh(&mut self) -> Result<(), Error> {
+        let refresh_token = match &self.refresh_token {
+            Some(refresh_token) => refresh_token.clone(),
+            None => {
+                return Err(Error {
+                    message: "The session has no refresh token".to_owned(),
+                })
+            }
+        };
+        let (access_token, refresh_token) =
+            matrix::refresh(&self.homeserver_url, refresh_token).await?;
+        self.access_token = access_token;
+        if refresh_token.is_some() {
+            self.refresh_token = refresh_token;
+        }
+
+        approve(
+            &normalize_url(&self.homeserver_url)?,
+            &self.user_id,
+            &self.access_token,
+            self.refresh_token.as_deref(),
+        )
+    }
+
+    /// Store the session and make it the default for its homeserver
+    pub fn store(&self, config: &mut Config) -> Result<(), Error> {
+        let homeserver_url = normalize_url(&self.homeserver_url)?;
+        config.set_str(
+            &format!("matrix.{}.homeserver", self.user_id),
+            &homeserver_url,
+        )?;
+        config.set_str(
+            &format!("matrix.{}.deviceId", self.user_id),
+            &self.device_id,
+        )?;
+        config.set_str(&format!("matrix.{}.user", homeserver_url), &self.user_id)?;
+
+        approve(
+            &homeserver_url,
+            &self.user_id,
+            &self.access_token,
+            self.refresh_token.as_deref(),
+        )
+    }
+
+    /// Remove the stored session, the access token is rejected with the credential helpers
+    pub fn remove(&self, config: &mut Config) -> Result<(), Error> {
+        let homeserver_url = normalize_url(&self.homeserver_url)?;
+        let _ = config.remove(&format!("matrix.{}.homeserver", self.user_id));
+        let _ = config.remove(&format!("matrix.{}.deviceId", self.user_id));
+        let default_user = format!("matrix.{}.user", homeserver_url);
+        if config.get_string(&default_user).ok().as_ref() == Some(&self.user_id) {
+            config.remove(&default_user)?;
+        }
+
+        reject(&homeserver_url, &self.user_id, &self.access_token)
+    }
+}
+
+/// Guest session stored for the homeserver
+///
+/// Guests can't do more than anyone with access to the homeserver, so their
+/// access token is kept in the config as
+///
+/// ```text
+/// [matrix "https://matrix.example.org"]
+///     guest = @123:example.org
+///     guestDeviceId = ABCDEFGH
+///     guestAccessToken = ...
+/// ```
+pub fn guest_session(
+    config: &Config,
+    homeserver_url: &str,
+) -> Result<Option<matrix::Session>, Error> {
+    let section = format!("matrix.{}", normalize_url(homeserver_url)?);
+    let (user_id, device_id, access_token) = match (
+        config.get_string(&format!("{}.guest", section)),
+        config.get_string(&format!("{}.guestDeviceId", section)),
+        config.get_string(&format!("{}.guestAccessToken", section)),
+    ) {
+        (Ok(user_id), Ok(device_id), Ok(access_token)) => (user_id, device_id, access_token),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(matrix::Session {
+        access_token,
+        user_id: UserId::try_from(&user_id[..])?,
+        device_id,
+    }))
+}
+
+/// Store the guest session for reuse by later invocations
+pub fn store_guest_session(
+    config: &mut Config,
+    homeserver_url: &str,
+    session: &matrix::Session,
+) -> Result<(), Error> {
+    let section = format!("matrix.{}", normalize_url(homeserver_url)?);
+    config.set_str(&format!("{}.guest", section), &session.user_id.to_string())?;
+    config.set_str(&format!("{}.guestDeviceId", section), &session.device_id)?;
+    config.set_str(
+        &format!("{}.guestAccessToken", section),
+        &session.access_token,
+    )?;
+
+    Ok(())
+}
+
+/// Homeserver URL without default port, path or trailing slash, as used for config keys
+pub fn normalize_url(url: &str) -> Result<String, Error> {
+    let url: url::Url = url.parse()?;
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Look up the access token, and refresh token, for `username` on the homeserver
+/// through `git credential`
+///
+/// Returns `None` if no credential helper has one, git is not allowed to prompt for it.
+pub fn fill(url: &str, username: &str) -> Result<Option<(String, Option<String>)>, Error> {
+    let output = match credential("fill", url, username, None, None)? {
+        Some(output) => output,
+        None => return Ok(None),
+    };
+
+    let find = |prefix| {
+        output
+            .lines()
+            .find_map(|line: &str| line.strip_prefix(prefix))
+            .map(|token| token.to_owned())
+    };
+    Ok(find("password=").map(|access_token| (access_token, find("oauth_refresh_token="))))
+}
+
+/// Store the access token with the configured credential helpers
+///
+/// The refresh token is only kept by helpers that support `oauth_refresh_token`.
+pub fn approve(
+    url: &str,
+    username: &str,
+    access_token: &str,
+    refresh_token: Option<&str>,
+) -> Result<(), Error> {
+    credential("approve", url, username, Some(access_token), refresh_token)?;
+    Ok(())
+}
+
+/// Remove the access token from the configured credential helpers
+pub fn reject(url: &str, username: &str, access_token: &str) -> Result<(), Error> {
+    credential("reject", url, username, Some(access_token), None)?;
+    Ok(())
+}
+
+fn credential(
+    action: &str,
+    url: &str,
+    username: &str,
+    password: Option<&str>,
+    refresh_token: Option<&str>,
+) -> Result<Option<String>, Error> {
+    let mut child = Command::new("git")
+        .args(["-c", "core.askPass=", "credential", action])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env_remove("GIT_ASKPASS")
+        .env_remove("SSH_ASKPASS")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        writeln!(stdin, "url={}", url)?;
+        writeln!(stdin, "username={}", username)?;
+        if let Some(password) = password {
+            writeln!(stdin, "password={}", password)?;
+        }
+        if let Some(refresh_token) = refresh_token {
+            writeln!(stdin, "oauth_refresh_token={}", refresh_token)?;
+        }
+        writeln!(stdin)?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
@@ -1,5 +1,10 @@
 use serde::Serialize;
 
+use crate::matrix;
+
+pub const SESSION_EXPIRED: &str =
+    "The Matrix session expired and can't be refreshed, run git matrix login";
+
 #[derive(Debug, Serialize)]
 pub struct Error {
     pub message: String,
@@ -15,6 +20,19 @@ impl From<git2::Error> for Error {
 
 impl From<ruma_client::Error> for Error {
     fn from(error: ruma_client::Error) -> Error {
+        match matrix::errcode(error) {
+            (Some(errcode), _) if errcode == "M_UNKNOWN_TOKEN" => Error {
+                message: SESSION_EXPIRED.to_owned(),
+            },
+            (_, error) => Error {
+                message: format!("{}", error),
+            },
+        }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(error: openssl::error::ErrorStack) -> Error {
         Error {
             message: format!("{}", error),
         }
@@ -37,6 +55,14 @@ impl From<std::env::VarError> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Error {
+        Error {
+            message: format!("{}", error),
+        }
+    }
+}
+
 impl From<url::ParseError> for Error {
     fn from(error: url::ParseError) -> Error {
         Error {
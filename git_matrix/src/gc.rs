@@ -0,0 +1,69 @@
+//! Consolidation of the room's packs
+//!
+//! Every push sends a pack, so a clone downloads as many packs as there were
+//! pushes. `gc` sends one pack with all objects of the current refs, marked as
+//! checkpoint, and fetching stops at the latest checkpoint. The checkpoint
+//! replaces the packs listed in the `org.gitmatrix.packs` event of the user.
+
+use ruma_client::identifiers::EventId;
+use std::convert::TryFrom;
+
+use crate::error::Error;
+use crate::{GitMatrix, PACK_EVENT_TYPE};
+
+impl GitMatrix {
+    /// Send a checkpoint pack with all objects reachable from the room's refs
+    ///
+    /// All packs are fetched first, so that the checkpoint doesn't depend on
+    /// what the local repository has. With `redact`, the superseded packs of the
+    /// room are redacted, which requires the power level for redacting events of
+    /// others. Returns the number of superseded packs.
+    pub async fn gc(&self, redact: bool) -> Result<usize, Error> {
+        let superseded = self.pack_event_ids().await?;
+        self.fetch_packs(false).await?;
+
+        let refs = self.refs().await?;
+        let mut shas: Vec<String> = refs.values().cloned().collect();
+        shas.sort();
+        shas.dedup();
+        let pack = self.git.pack_commits(&shas)?;
+
+        // the checkpoint would supersede the pack of a push since reading the
+        // packs and refs without having its objects
+        if self.pack_event_ids().await? != superseded || self.refs().await? != refs {
+            return Err(Error {
+                message:
+                    "The remote was pushed to while collecting garbage, run git matrix gc again"
+                        .to_owned(),
+            });
+        }
+
+        let entry = self.send_pack(pack, shas, true).await?;
+
+        self.update_pack_manifest(entry, true).await?;
+
+        if redact {
+            for event_id in &superseded {
+                self.matrix
+                    .redact(
+                        EventId::try_from(&event_id[..])?,
+                        "Superseded by git matrix gc",
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(superseded.len())
+    }
+
+    /// IDs of the room's pack events, oldest first
+    async fn pack_event_ids(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .matrix
+            .messages(PACK_EVENT_TYPE)
+            .await?
+            .into_iter()
+            .filter_map(|event| event["event_id"].as_str().map(|id| id.to_owned()))
+            .collect())
+    }
+}
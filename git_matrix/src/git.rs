@@ -32,6 +32,32 @@ impl Git {
         })
     }
 
+    /// Pack of all objects reachable from the commits
+    pub fn pack_commits(&self, shas: &[String]) -> Result<Pack, git2::Error> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TIME)?;
+        for sha in shas {
+            revwalk.push(git2::Oid::from_str(sha)?)?;
+        }
+
+        let mut packbuilder = self.repo.packbuilder()?;
+        packbuilder.insert_walk(&mut revwalk)?;
+
+        let mut buf = Buf::new();
+        packbuilder.write_buf(&mut buf)?;
+
+        Ok(Pack {
+            content: buf.to_vec(),
+        })
+    }
+
+    /// Branch HEAD points to, `None` if detached
+    pub fn head_branch(&self) -> Option<String> {
+        let head = self.repo.find_reference("HEAD").ok()?;
+        let target = head.symbolic_target()?;
+        Some(target.trim_start_matches("refs/heads/").to_owned())
+    }
+
     pub fn ref_id(&self, src: &str) -> Result<String, Error> {
         Ok(self.repo.refname_to_id(src)?.to_string())
     }
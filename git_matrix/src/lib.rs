@@ -1,22 +1,50 @@
 // #![warn(missing_docs)]
 
+use credential::Credentials;
 use error::Error;
-use ruma_client::events::collections::all::RoomEvent;
+use packs::{PackEntry, PACKS_EVENT_TYPE};
+use protection::{
+    Authorization, ProtectionEventContent, POWER_LEVELS_EVENT_TYPE, PROTECTION_EVENT_TYPE,
+};
+use ruma_client::events::collections::all::{RoomEvent, StateEvent};
+use ruma_client::identifiers::{RoomIdOrAliasId, UserId};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use signing::{
+    PushCertEventContent, RefSignature, TrustedKeysEventContent, Verification,
+    PUSH_CERT_EVENT_TYPE, TRUSTED_KEYS_EVENT_TYPE, ZERO_SHA,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::io::Write;
+use user_refs::{UserRefsEventContent, USER_REFS_EVENT_TYPE};
 
 pub use git2;
 
+pub mod attachment;
+pub mod bundle;
+pub mod collaborators;
+pub mod credential;
 pub mod error;
+pub mod gc;
 pub mod git;
 pub mod matrix;
+pub mod mirror;
+pub mod packs;
+pub mod protection;
+pub mod signing;
+pub mod user_refs;
 
 type Refs = HashMap<String, String>;
 
-#[derive(Serialize, Deserialize)]
+/// Refs with the verification of their last update
+pub type VerifiedRefs = HashMap<String, (String, Verification)>;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RefEventContent {
     sha: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<RefSignature>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,6 +55,56 @@ pub struct PackFile {
 #[derive(Serialize, Deserialize)]
 pub struct PackEventContent {
     content_uri: String,
+    /// Key and hash of the pack if it was encrypted before uploading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<attachment::EncryptedFile>,
+    /// Set by `git matrix gc`, the pack has all objects of the refs at the time
+    /// and supersedes the packs sent before it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    checkpoint: bool,
+}
+
+pub const PACK_EVENT_TYPE: &str = "org.gitmatrix.pack";
+
+pub const REPO_EVENT_TYPE: &str = "org.gitmatrix.repo";
+
+/// Version of the pack and ref event formats this crate reads and writes
+pub const FORMAT_VERSION: u64 = 1;
+
+/// Content of the `org.gitmatrix.repo` state event, marks the room as repository
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RepoEventContent {
+    /// Version of the pack and ref event formats used in the room
+    #[serde(default = "format_version")]
+    pub version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_branch: Option<String>,
+    /// `public` or `private`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+}
+
+impl Default for RepoEventContent {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            description: None,
+            default_branch: None,
+            visibility: None,
+            license: None,
+            homepage: None,
+        }
+    }
+}
+
+fn format_version() -> u64 {
+    FORMAT_VERSION
 }
 
 /// Create a new GitMatrix
@@ -51,153 +129,589 @@ impl GitMatrixBuilder {
 
     /// Build the GitMatrix
     pub async fn build(self) -> Result<GitMatrix, Error> {
-        let (homeserver_url, room) = self.parse_remote_url()?;
-
-        let credentials = self.credentials();
+        let (homeserver_url, room) = self.resolve_remote_url().await?;
 
         let git = git::Git::new()?;
 
-        let matrix = match credentials {
-            Ok((url, username, access_token, device_id)) => {
-                let homeserver_url = url;
-                matrix::Builder::new(homeserver_url, room)
-                    .session(&username, access_token, device_id)
-                    .await?
-            }
-            Err(_) => {
-                let (matrix, _) = matrix::Builder::new(homeserver_url, room).guest().await?;
+        let credentials = self.credentials(&git, &homeserver_url).await?;
+
+        let matrix = match &credentials {
+            Some(credentials) => {
+                let homeserver_url = credentials.homeserver_url.clone();
+                let matrix = matrix::Builder::new(homeserver_url, room.clone())
+                    .session(credentials)
+                    .await?;
+                // aliases move to the replacement room on upgrades, room IDs don't
+                let resolved = matrix.room_id();
+                let matrix = follow_tombstones(matrix).await;
+                let room = if matrix.room_id() == resolved {
+                    room
+                } else {
+                    matrix.room_id()
+                };
+                join(&matrix, &room, credentials).await?;
                 matrix
             }
+            None => {
+                let mut config = git::get_config()?;
+                let stored = credential::guest_session(&config, &homeserver_url)?;
+                let (matrix, session) = matrix::Builder::new(homeserver_url.clone(), room)
+                    .guest(stored.clone())
+                    .await?;
+                if let Some(session) = session.filter(|session| Some(session) != stored.as_ref()) {
+                    credential::store_guest_session(&mut config, &homeserver_url, &session)?;
+                }
+                follow_tombstones(matrix).await
+            }
+        };
+
+        let repo = match matrix.get_state_event(REPO_EVENT_TYPE, "").await {
+            Ok(Some(content)) => Some(serde_json::from_value::<RepoEventContent>(content)?),
+            Ok(None) => None,
+            Err(error) => {
+                return Err(match &credentials {
+                    Some(credentials) => session_error(error, credentials),
+                    None => error.into(),
+                })
+            }
         };
+        if let Some(repo) = &repo {
+            if repo.version > FORMAT_VERSION {
+                return Err(Error {
+                    message: format!(
+                        "The room uses repository format {}, but only format {} is supported, upgrade git-matrix",
+                        repo.version, FORMAT_VERSION
+                    ),
+                });
+            }
+        }
 
-        Ok(GitMatrix { git, matrix })
+        let encrypt_packs = self.remote_flag(&git, "matrixEncryptPacks");
+        let sign_refs = self.remote_flag(&git, "matrixSignRefs");
+        Ok(GitMatrix {
+            git,
+            matrix,
+            repo,
+            encrypt_packs,
+            sign_refs,
+            push_cert: false,
+        })
     }
 
-    fn credentials(&self) -> Result<(String, String, String, String), Error> {
-        let config = git::get_config()?;
-        let url = config.get_string("credential.matrix.url")?;
-        let username = config.get_string("credential.matrix.username")?;
-        let access_token = config.get_string("credential.matrix.access-token")?;
-        let device_id = config.get_string("credential.matrix.device-id")?;
+    /// Homeserver of the remote and the session used for it, `None` if acting as guest
+    pub async fn session(&self) -> Result<(String, Option<Credentials>), Error> {
+        let (homeserver_url, _) = self.resolve_remote_url().await?;
+        let git = git::Git::new()?;
+        let credentials = self.credentials(&git, &homeserver_url).await?;
 
-        Ok((url, username, access_token, device_id))
+        Ok((homeserver_url, credentials))
     }
 
-    fn parse_remote_url(&self) -> Result<(String, String), Error> {
-        let url: url::Url = self.remote_url.parse()?;
-        let scheme = url.scheme();
-        let host = match url.host() {
-            Some(host) => host.to_string(),
+    /// Create the room for the remote URL
+    ///
+    /// Public repositories can be joined and read by guests, writing refs and packs
+    /// requires power level 50 either way.
+    pub async fn create(self, repo: RepoEventContent) -> Result<GitMatrix, Error> {
+        let public = repo.visibility.as_deref() == Some("public");
+        let (homeserver_url, room) = self.resolve_remote_url().await?;
+
+        let git = git::Git::new()?;
+
+        let credentials = match self.credentials(&git, &homeserver_url).await? {
+            Some(credentials) => credentials,
             None => {
                 return Err(Error {
-                    message: "Invalid host in URL".to_owned(),
+                    message: format!(
+                        "Creating a repository requires a login to {}, run git matrix",
+                        homeserver_url
+                    ),
                 })
             }
         };
 
-        let homeserver_url = match scheme {
-            "matrix" => format!("https://{}", host),
+        let (join_rule, history_visibility, guest_access) = if public {
+            ("public", "world_readable", "can_join")
+        } else {
+            ("invite", "shared", "forbidden")
+        };
+        let settings = matrix::RoomSettings {
+            public,
+            initial_state: vec![
+                json!({
+                    "type": "m.room.join_rules",
+                    "state_key": "",
+                    "content": { "join_rule": join_rule },
+                }),
+                json!({
+                    "type": "m.room.history_visibility",
+                    "state_key": "",
+                    "content": { "history_visibility": history_visibility },
+                }),
+                json!({
+                    "type": "m.room.guest_access",
+                    "state_key": "",
+                    "content": { "guest_access": guest_access },
+                }),
+                json!({
+                    "type": REPO_EVENT_TYPE,
+                    "state_key": "",
+                    "content": repo,
+                }),
+            ],
+            // the override replaces the homeserver's `events` levels as a whole, so
+            // the defaults for the room's own events have to be repeated
+            power_levels: json!({
+                "events": {
+                    "m.room.name": 50,
+                    "m.room.avatar": 50,
+                    "m.room.canonical_alias": 50,
+                    "m.room.power_levels": 100,
+                    "m.room.history_visibility": 100,
+                    "m.room.join_rules": 100,
+                    "m.room.guest_access": 100,
+                    "m.room.tombstone": 100,
+                    "m.room.server_acl": 100,
+                    "m.room.encryption": 100,
+                    "org.gitmatrix.refs": 50,
+                    "org.gitmatrix.pack": 50,
+                    (PROTECTION_EVENT_TYPE): 100,
+                    (TRUSTED_KEYS_EVENT_TYPE): 100,
+                    (PUSH_CERT_EVENT_TYPE): 50,
+                    (PACKS_EVENT_TYPE): 50,
+                    (USER_REFS_EVENT_TYPE): 50,
+                    (REPO_EVENT_TYPE): 100,
+                },
+            }),
+        };
+
+        let matrix = matrix::Builder::new(credentials.homeserver_url.clone(), room)
+            .create(&credentials, settings)
+            .await?;
+
+        let encrypt_packs = self.remote_flag(&git, "matrixEncryptPacks");
+        let sign_refs = self.remote_flag(&git, "matrixSignRefs");
+        Ok(GitMatrix {
+            git,
+            matrix,
+            repo: Some(repo),
+            encrypt_packs,
+            sign_refs,
+            push_cert: false,
+        })
+    }
+
+    /// Boolean remote option, `remote.<name>.matrixEncryptPacks` to encrypt packs
+    /// before uploading them or `remote.<name>.matrixSignRefs` to sign ref updates
+    fn remote_flag(&self, git: &git::Git, key: &str) -> bool {
+        git.repo
+            .config()
+            .and_then(|config| config.get_bool(&format!("remote.{}.{}", self.remote_name, key)))
+            .unwrap_or(false)
+    }
+
+    /// Session to use for the remote, an access token from the environment takes
+    /// precedence over `remote.<name>.matrixUser`, which takes precedence over the
+    /// user last logged in to the remote's homeserver
+    async fn credentials(
+        &self,
+        git: &git::Git,
+        homeserver_url: &str,
+    ) -> Result<Option<Credentials>, Error> {
+        if let Some(credentials) = Credentials::from_env(homeserver_url).await? {
+            return Ok(Some(credentials));
+        }
+
+        let config = git.repo.config()?;
+        match config.get_string(&format!("remote.{}.matrixUser", self.remote_name)) {
+            Ok(user_id) => Credentials::for_user(&config, &user_id),
+            Err(_) => Credentials::for_homeserver(&config, homeserver_url),
+        }
+    }
+
+    /// Homeserver URL and room alias or ID of the remote URL
+    async fn resolve_remote_url(&self) -> Result<(String, String), Error> {
+        let (homeserver, room) = parse_remote_url(&self.remote_url)?;
+
+        Ok((homeserver.url().await?, room))
+    }
+}
+
+/// Homeserver a remote URL names
+#[derive(Debug, PartialEq)]
+enum Homeserver {
+    /// Server name to look up the homeserver it delegates to for
+    ServerName(String),
+    /// Client-server API base URL
+    Url(String),
+}
+
+impl Homeserver {
+    async fn url(self) -> Result<String, Error> {
+        match self {
+            Homeserver::ServerName(server_name) => {
+                matrix::discover_homeserver(&format!("https://{}", server_name)).await
+            }
+            Homeserver::Url(url) => Ok(url),
+        }
+    }
+}
+
+/// Homeserver and room alias or ID of a remote URL
+///
+/// Supported are `matrix://<server-name>/<room>`, `<scheme>://<host>[:port]/<room>`,
+/// where `<room>` is an alias local part, `#alias:server` or `!id:server`,
+/// `https://matrix.to/#/<room>` and `matrix:r/<alias>` or `matrix:roomid/<id>`.
+/// `matrix://` URLs and URLs that only name a room use the homeserver the server
+/// name delegates to, or the first `via` server.
+fn parse_remote_url(remote_url: &str) -> Result<(Homeserver, String), Error> {
+    let url: url::Url = remote_url.parse()?;
+    let scheme = url.scheme();
+
+    if scheme == "matrix" && url.cannot_be_a_base() {
+        let room = match url.path().split_once('/') {
+            Some(("r", alias)) => format!("#{}", decode(alias)?),
+            Some(("roomid", id)) => format!("!{}", decode(id)?),
             _ => {
-                let port = match url.port_or_known_default() {
-                    Some(port) => port,
-                    None => {
-                        return Err(Error {
-                            message: "Could not detect port in URL".to_owned(),
-                        })
-                    }
-                };
-                format!("{}://{}:{}", scheme, host, port)
+                return Err(Error {
+                    message: "Only matrix:r/ and matrix:roomid/ URIs are supported".to_owned(),
+                })
             }
         };
+        let server_name = room_server_name(url.query(), &room)?;
+        return Ok((Homeserver::ServerName(server_name), room));
+    }
 
-        let room = match url.path_segments() {
-            Some(segments) => {
-                let segments: Vec<&str> = segments.collect();
-                if segments.len() != 1 {
-                    return Err(Error {
-                        message: "Invalid path length in URL".to_owned(),
-                    });
-                }
-                let room = format!("#{}:{}", segments[0], host);
-                room
-            }
-            None => {
+    let host = match url.host() {
+        Some(host) => host.to_string(),
+        None => {
+            return Err(Error {
+                message: "Invalid host in URL".to_owned(),
+            })
+        }
+    };
+
+    if host == "matrix.to" {
+        // the query is part of the fragment, #/<room>?via=<server>
+        let (fragment, query) = match url.fragment().unwrap_or_default().split_once('?') {
+            Some((fragment, query)) => (fragment, Some(query)),
+            None => (url.fragment().unwrap_or_default(), None),
+        };
+        let fragment = decode(fragment)?;
+        let room = match fragment.trim_start_matches('/').split('/').next() {
+            Some(room) if room.starts_with('#') || room.starts_with('!') => room.to_owned(),
+            _ => {
                 return Err(Error {
-                    message: "Invalid path in URL".to_owned(),
+                    message: "matrix.to link doesn't point to a room".to_owned(),
                 })
             }
         };
+        let server_name = room_server_name(query, &room)?;
+        return Ok((Homeserver::ServerName(server_name), room));
+    }
+
+    let homeserver = match scheme {
+        "matrix" => Homeserver::ServerName(host.clone()),
+        _ => {
+            let port = match url.port_or_known_default() {
+                Some(port) => port,
+                None => {
+                    return Err(Error {
+                        message: "Could not detect port in URL".to_owned(),
+                    })
+                }
+            };
+            Homeserver::Url(format!("{}://{}:{}", scheme, host, port))
+        }
+    };
 
-        Ok((homeserver_url, room))
+    // an unencoded # starts the fragment, matrix://example.org/#repo:example.com
+    if let Some(fragment) = url.fragment() {
+        return Ok((homeserver, format!("#{}", decode(fragment)?)));
+    }
+
+    let room = match url.path_segments() {
+        Some(segments) => {
+            let segments: Vec<&str> = segments.collect();
+            if segments.len() != 1 {
+                return Err(Error {
+                    message: "Invalid path length in URL".to_owned(),
+                });
+            }
+            let segment = decode(segments[0])?;
+            if segment.starts_with('#') || segment.starts_with('!') {
+                segment
+            } else {
+                format!("#{}:{}", segment, host)
+            }
+        }
+        None => {
+            return Err(Error {
+                message: "Invalid path in URL".to_owned(),
+            })
+        }
+    };
+
+    Ok((homeserver, room))
+}
+
+/// The room that replaced the remote's room through upgrades, the room itself if
+/// it wasn't upgraded or its tombstone can't be read
+async fn follow_tombstones(mut matrix: matrix::Matrix) -> matrix::Matrix {
+    let mut rooms = vec![matrix.room_id()];
+    while let Ok(Some(room_id)) = matrix.replacement_room().await {
+        if rooms.contains(&room_id.to_string()) {
+            break;
+        }
+        rooms.push(room_id.to_string());
+        matrix = matrix.with_room(room_id);
+    }
+
+    matrix
+}
+
+/// Join the room unless the session's user is a member already, accepting a
+/// pending invite
+async fn join(matrix: &matrix::Matrix, room: &str, credentials: &Credentials) -> Result<(), Error> {
+    match matrix.membership().await {
+        Ok(Some(membership)) if membership == "join" => return Ok(()),
+        Ok(_) => (),
+        Err(error) => return Err(session_error(error, credentials)),
+    }
+
+    match matrix.join(RoomIdOrAliasId::try_from(room)?).await {
+        Ok(()) => Ok(()),
+        Err(error) => match matrix::errcode(error) {
+            (Some(errcode), _) if errcode == "M_FORBIDDEN" => Err(Error {
+                message: format!(
+                    "{} can't join {}, the room requires an invite, ask a maintainer to run git matrix collaborators add {}",
+                    credentials.user_id, room, credentials.user_id
+                ),
+            }),
+            (_, error) => Err(session_error(error, credentials)),
+        },
+    }
+}
+
+/// Error for a request made with the stored session, rejecting its access token
+/// with the credential helpers if it expired
+fn session_error(error: ruma_client::Error, credentials: &Credentials) -> Error {
+    match matrix::errcode(error) {
+        (Some(errcode), _) if errcode == "M_UNKNOWN_TOKEN" => {
+            if let Err(error) = credential::reject(
+                &credentials.homeserver_url,
+                &credentials.user_id,
+                &credentials.access_token,
+            ) {
+                return error;
+            }
+            Error {
+                message: error::SESSION_EXPIRED.to_owned(),
+            }
+        }
+        (_, error) => error.into(),
     }
 }
 
+/// Server of a room named without homeserver, the first `via` server or the room's server
+fn room_server_name(query: Option<&str>, room: &str) -> Result<String, Error> {
+    let via = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "via")
+        .map(|(_, server)| server.into_owned());
+    let server_name = match (via, room.find(':')) {
+        (Some(server_name), _) => server_name,
+        (None, Some(separator)) => room[separator + 1..].to_owned(),
+        (None, None) => {
+            return Err(Error {
+                message: format!("Can't tell the server of {}, add ?via=<server>", room),
+            })
+        }
+    };
+
+    Ok(server_name)
+}
+
+fn decode(component: &str) -> Result<String, Error> {
+    Ok(percent_encoding::percent_decode_str(component)
+        .decode_utf8()?
+        .into_owned())
+}
+
 pub struct GitMatrix {
     git: git::Git,
     matrix: matrix::Matrix,
+    repo: Option<RepoEventContent>,
+    encrypt_packs: bool,
+    sign_refs: bool,
+    push_cert: bool,
 }
 
 impl GitMatrix {
+    /// Repository metadata, `None` for rooms without `org.gitmatrix.repo` event
+    pub fn repo(&self) -> Option<&RepoEventContent> {
+        self.repo.as_ref()
+    }
+
+    /// Store a push certificate with each ref update, as for `git push --signed`
+    pub fn set_push_cert(&mut self, push_cert: bool) {
+        self.push_cert = push_cert;
+    }
+
     pub async fn push(&self, src: &str, dst: &str) -> Result<(), Error> {
-        let pack = self.git.pack(src)?;
+        self.check_protection(dst).await?;
 
+        let sha = self.git.ref_id(src)?;
+        let entry = self
+            .send_pack(self.git.pack(src)?, vec![sha.clone()], false)
+            .await?;
+        self.send_ref(dst, sha).await?;
+
+        self.update_pack_manifest(entry, false).await
+    }
+
+    /// Point `dst` to `sha`, signed and with push certificate if set
+    async fn send_ref(&self, dst: &str, sha: String) -> Result<(), Error> {
+        let old_sha = self.current_sha(dst).await?;
+        let signature = if self.sign_refs || self.trusted_keys().await?.required {
+            Some(self.sign_ref_update(&self.matrix.room_id(), dst, &old_sha, &sha)?)
+        } else {
+            None
+        };
+        let push_cert = if self.push_cert {
+            Some(self.push_certificate(dst, &old_sha, &sha)?)
+        } else {
+            None
+        };
+
+        let git_ref = RefEventContent { sha, signature };
+        match user_refs::owner(dst) {
+            Some(owner) => {
+                let mut user_refs = self.user_refs(&owner).await?;
+                user_refs.refs.insert(dst.to_owned(), git_ref);
+                self.matrix
+                    .send_state_event_for_key(USER_REFS_EVENT_TYPE, &owner, user_refs)
+                    .await?;
+            }
+            None => {
+                self.matrix
+                    .send_state_event_for_key("org.gitmatrix.refs", dst, git_ref)
+                    .await?;
+            }
+        }
+        if let Some(push_cert) = push_cert {
+            self.matrix
+                .send_state_event_for_key(PUSH_CERT_EVENT_TYPE, dst, push_cert)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Upload the pack, encrypted if set for the remote, and send its
+    /// `org.gitmatrix.pack` event
+    ///
+    /// Returns the pack's entry for the manifest, the pack has all objects of `tips`.
+    async fn send_pack(
+        &self,
+        pack: git::Pack,
+        tips: Vec<String>,
+        checkpoint: bool,
+    ) -> Result<PackEntry, Error> {
+        let (content_type, content, encryption) = if self.encrypt_packs {
+            let (ciphertext, encryption) = attachment::encrypt(&pack.content)?;
+            ("application/octet-stream", ciphertext, Some(encryption))
+        } else {
+            ("gitpack", pack.content, None)
+        };
+        let size = content.len() as u64;
+        let sha256 = attachment::hash(&content);
         let response = self
             .matrix
-            .create_content("pack", "gitpack", pack.content)
+            .create_content("pack", content_type, content)
             .await?;
 
         let pack_event = serde_json::to_value(PackEventContent {
-            content_uri: response.content_uri,
+            content_uri: response.content_uri.clone(),
+            encryption: encryption.clone(),
+            checkpoint,
         })?;
 
         self.matrix
-            .send_custom_event("org.gitmatrix.pack", pack_event)
-            .await?;
-
-        self.matrix
-            .send_state_event_for_key(
-                "org.gitmatrix.refs",
-                dst,
-                RefEventContent {
-                    sha: self.git.ref_id(src)?,
-                },
-            )
+            .send_custom_event(PACK_EVENT_TYPE, pack_event)
             .await?;
 
-        Ok(())
+        Ok(PackEntry {
+            content_uri: response.content_uri,
+            size,
+            sha256,
+            tips,
+            encryption,
+        })
     }
 
+    /// Download the packs sent since the latest checkpoint
     pub async fn fetch(&self) -> Result<(), Error> {
-        let response = self
-            .matrix
-            .sync(vec!["org.gitmatrix.pack".to_owned()])
-            .await
-            .unwrap();
+        self.fetch_packs(true).await
+    }
+
+    /// Download the packs of the room, newest first, up to the latest checkpoint
+    /// if `from_checkpoint` is set and all of them otherwise
+    ///
+    /// Packs are read by paginating the timeline, the initial sync only has its
+    /// latest events and would miss older checkpoints.
+    async fn fetch_packs(&self, from_checkpoint: bool) -> Result<(), Error> {
         let odb = self.git.repo.odb().unwrap();
 
-        for (_, room) in response.rooms.join {
-            for event in room.timeline.events.into_iter().rev() {
-                match event.into_result() {
-                    Ok(event) => match event {
-                        RoomEvent::CustomRoom(event) => {
-                            let object: crate::PackEventContent =
-                                serde_json::from_value(event.content).unwrap();
-                            let uri = url::Url::parse(&object.content_uri).unwrap();
-                            let server_name = uri.host().unwrap().to_string();
-                            let media_id = uri.path_segments().unwrap().next().unwrap().to_string();
-                            let response = self
-                                .matrix
-                                .get_content(media_id, server_name)
-                                .await
-                                .unwrap();
-
-                            let mut packwriter = odb.packwriter().unwrap();
-                            packwriter.write(&response.file).unwrap();
-                            packwriter.commit().unwrap();
-                        }
-                        _ => (),
-                    },
-                    Err(_) => (),
+        // pack events redacted by git matrix gc have no content and are skipped
+        let packs: Vec<PackEventContent> = self
+            .matrix
+            .messages(PACK_EVENT_TYPE)
+            .await?
+            .into_iter()
+            .rev()
+            .filter_map(|event| serde_json::from_value(event["content"].clone()).ok())
+            .collect();
+
+        for pack in packs {
+            self.fetch_pack(&odb, &pack).await?;
+            if from_checkpoint && pack.checkpoint {
+                return Ok(());
+            }
+        }
+
+        self.fetch_predecessors(&odb, from_checkpoint).await
+    }
+
+    /// Download the packs pushed before the room was upgraded, which stay in the
+    /// rooms it replaced
+    async fn fetch_predecessors(
+        &self,
+        odb: &git2::Odb<'_>,
+        from_checkpoint: bool,
+    ) -> Result<(), Error> {
+        let mut room = self.matrix.clone();
+        while let Some(room_id) = room.predecessor().await? {
+            room = room.with_room(room_id);
+            let events = match room.messages(PACK_EVENT_TYPE).await {
+                Ok(events) => events,
+                Err(error) => {
+                    return Err(Error {
+                        message: format!(
+                            "Can't read the packs of {}, which the room was upgraded from: {}",
+                            room.room_id(),
+                            Error::from(error).message
+                        ),
+                    })
+                }
+            };
+            for event in events.into_iter().rev() {
+                let pack: PackEventContent = match serde_json::from_value(event["content"].clone())
+                {
+                    Ok(pack) => pack,
+                    Err(_) => continue,
+                };
+                self.fetch_pack(odb, &pack).await?;
+                if from_checkpoint && pack.checkpoint {
+                    return Ok(());
                 }
             }
         }
@@ -205,34 +719,712 @@ impl GitMatrix {
         Ok(())
     }
 
+    /// Upgrade the room to `room_version` and carry the repository's state over
+    ///
+    /// Returns the ID of the new room, the alias is moved to it by the homeserver.
+    /// Personal refs of other users can only be set by their owners, who have to
+    /// push them again. Signatures of ref updates name the room and the pusher,
+    /// so signed refs are signed again by the upgrader for the new room, and push
+    /// certificates stay in the old room.
+    pub async fn upgrade_room(&self, room_version: &str) -> Result<String, Error> {
+        let user_id = match self.matrix.user_id() {
+            Some(user_id) => user_id.to_string(),
+            None => {
+                return Err(Error {
+                    message: "Upgrading the room requires a Matrix session".to_owned(),
+                })
+            }
+        };
+
+        let required = self.trusted_keys().await?.required;
+        let needs_signature = |git_ref: &RefEventContent| git_ref.signature.is_some() || required;
+
+        // events are read before the upgrade tombstones the room, refs are kept
+        // by name to be signed again
+        let mut events = Vec::new();
+        for event in self.matrix.room_state().await? {
+            let event_type = event["type"].as_str().unwrap_or_default().to_owned();
+            let state_key = event["state_key"].as_str().unwrap_or_default().to_owned();
+            if !event_type.starts_with("org.gitmatrix.")
+                || (state_key.starts_with('@') && state_key != user_id)
+                || event_type == PUSH_CERT_EVENT_TYPE
+            {
+                continue;
+            }
+
+            let refs: Option<BTreeMap<String, RefEventContent>> =
+                if event_type == "org.gitmatrix.refs" {
+                    let git_ref = serde_json::from_value(event["content"].clone())?;
+                    Some(vec![(state_key.clone(), git_ref)].into_iter().collect())
+                } else if event_type == USER_REFS_EVENT_TYPE {
+                    let user_refs: UserRefsEventContent =
+                        serde_json::from_value(event["content"].clone())?;
+                    Some(user_refs.refs)
+                } else {
+                    None
+                };
+            events.push((event_type, state_key, event["content"].clone(), refs));
+        }
+
+        // signatures name the room, so they can only be made once the replacement
+        // room exists. Signing once beforehand fails for a missing or unusable
+        // signing key while the room is still left as it is.
+        if events
+            .iter()
+            .filter_map(|(_, _, _, refs)| refs.as_ref())
+            .flat_map(|refs| refs.values())
+            .any(needs_signature)
+        {
+            self.sign_ref_update(&self.matrix.room_id(), "HEAD", ZERO_SHA, ZERO_SHA)?;
+        }
+
+        let replacement = self
+            .matrix
+            .with_room(self.matrix.upgrade(room_version).await?);
+
+        for (event_type, state_key, content, refs) in events.iter_mut() {
+            let refs = match refs {
+                Some(refs) => refs,
+                None => continue,
+            };
+            for (ref_name, git_ref) in refs.iter_mut() {
+                if needs_signature(git_ref) {
+                    git_ref.signature = Some(self.sign_ref_update(
+                        &replacement.room_id(),
+                        ref_name,
+                        ZERO_SHA,
+                        &git_ref.sha,
+                    )?);
+                }
+            }
+            *content = if event_type == USER_REFS_EVENT_TYPE {
+                serde_json::to_value(UserRefsEventContent { refs: refs.clone() })?
+            } else {
+                serde_json::to_value(&refs[&state_key[..]])?
+            };
+        }
+
+        for (event_type, state_key, content, _) in events {
+            replacement
+                .send_state_event_for_key(&event_type, &state_key, content)
+                .await?;
+        }
+
+        Ok(replacement.room_id())
+    }
+
+    /// Download the pack of an `org.gitmatrix.pack` event into the object database
+    async fn fetch_pack(
+        &self,
+        odb: &git2::Odb<'_>,
+        object: &PackEventContent,
+    ) -> Result<(), Error> {
+        let file = self.download(&object.content_uri).await?;
+        write_pack(odb, file, object.encryption.as_ref())
+    }
+
+    /// Download an uploaded file by its `mxc://` URI
+    async fn download(&self, content_uri: &str) -> Result<Vec<u8>, Error> {
+        let uri = url::Url::parse(content_uri)?;
+        let server_name = uri.host().unwrap().to_string();
+        let media_id = uri.path_segments().unwrap().next().unwrap().to_string();
+        let response = self.matrix.get_content(media_id, server_name).await?;
+
+        Ok(response.file)
+    }
+
     pub async fn refs(&self) -> Result<Refs, Error> {
+        Ok(self
+            .verified_refs()
+            .await?
+            .into_iter()
+            .map(|(ref_name, (sha, _))| (ref_name, sha))
+            .collect())
+    }
+
+    /// Refs with the verification of the signature of their last update
+    ///
+    /// Updates that aren't signed with a trusted key are left out if the room
+    /// requires signatures.
+    pub async fn verified_refs(&self) -> Result<VerifiedRefs, Error> {
+        if !self.matrix.has_credentials() {
+            return self.refs_from_state().await;
+        }
+
         let response = self
             .matrix
-            .sync(vec!["org.gitmatrix.refs".to_owned()])
-            .await
-            .unwrap();
+            .sync(
+                vec![
+                    "org.gitmatrix.refs".to_owned(),
+                    USER_REFS_EVENT_TYPE.to_owned(),
+                    PROTECTION_EVENT_TYPE.to_owned(),
+                    POWER_LEVELS_EVENT_TYPE.to_owned(),
+                    TRUSTED_KEYS_EVENT_TYPE.to_owned(),
+                ],
+                None,
+            )
+            .await?;
 
-        let mut refs: Refs = HashMap::new();
+        let mut ref_state = RefState::new(self.git.repo.config()?, self.matrix.room_id(), false);
         for (_, room) in response.rooms.join {
-            for event in room.timeline.events.into_iter().rev() {
+            // state holds the room as of the start of the timeline, replaying the
+            // timeline in order authorizes each ref update against the rules back then.
+            // The updates before the timeline are unknown, so the previous commit
+            // of the refs in the state is only checked for the timeline's updates.
+            let mut state: Vec<StateEvent> = room
+                .state
+                .events
+                .into_iter()
+                .filter_map(|event| event.into_result().ok())
+                .collect();
+            // apply power levels before the rules they authorize, and rules before refs
+            state.sort_by_key(|event| match event {
+                StateEvent::RoomPowerLevels(_) => 0,
+                StateEvent::CustomState(event)
+                    if event.event_type == "org.gitmatrix.refs"
+                        || event.event_type == USER_REFS_EVENT_TYPE =>
+                {
+                    2
+                }
+                _ => 1,
+            });
+            for event in state {
+                match event {
+                    StateEvent::RoomPowerLevels(event) => {
+                        ref_state.authorization.power_levels =
+                            serde_json::from_value(serde_json::to_value(event.content)?)?;
+                    }
+                    StateEvent::CustomState(event) => {
+                        ref_state.apply(
+                            (&event.event_type, &event.state_key, &event.sender),
+                            event.content,
+                        );
+                    }
+                    _ => (),
+                }
+            }
+            ref_state.replayed = true;
+            for event in room.timeline.events {
                 match event.into_result() {
-                    Ok(event) => match event {
-                        RoomEvent::CustomState(event) => {
-                            if event.event_type == "org.gitmatrix.refs" {
-                                let git_ref: RefEventContent =
-                                    serde_json::from_value(event.content).unwrap();
-                                if !refs.contains_key(&event.state_key) {
-                                    refs.insert(event.state_key, git_ref.sha);
-                                }
-                            }
-                        }
-                        _ => (),
-                    },
-                    Err(_) => (),
+                    Ok(RoomEvent::RoomPowerLevels(event)) => {
+                        ref_state.authorization.power_levels =
+                            serde_json::from_value(serde_json::to_value(event.content)?)?;
+                    }
+                    Ok(RoomEvent::CustomState(event)) => {
+                        ref_state.apply(
+                            (&event.event_type, &event.state_key, &event.sender),
+                            event.content,
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(ref_state.refs)
+    }
+
+    /// Refs in the current room state, for guests and reading without session
+    ///
+    /// Without the timeline updates are authorized against the current rules,
+    /// rather than the rules at the time, and signatures are checked without
+    /// knowing the previous commit.
+    async fn refs_from_state(&self) -> Result<VerifiedRefs, Error> {
+        refs_from_state_events(
+            self.git.repo.config()?,
+            self.matrix.room_id(),
+            self.matrix.room_state().await?,
+        )
+    }
+
+    /// Commit `ref_name` currently points to in the room state
+    async fn current_sha(&self, ref_name: &str) -> Result<String, Error> {
+        if let Some(owner) = user_refs::owner(ref_name) {
+            return Ok(match self.user_refs(&owner).await?.refs.remove(ref_name) {
+                Some(git_ref) => git_ref.sha,
+                None => ZERO_SHA.to_owned(),
+            });
+        }
+
+        match self
+            .matrix
+            .get_state_event("org.gitmatrix.refs", ref_name)
+            .await?
+        {
+            Some(content) => Ok(serde_json::from_value::<RefEventContent>(content)?.sha),
+            None => Ok(ZERO_SHA.to_owned()),
+        }
+    }
+
+    /// Personal refs of the user in the room state
+    async fn user_refs(&self, user_id: &str) -> Result<UserRefsEventContent, Error> {
+        match self
+            .matrix
+            .get_state_event(USER_REFS_EVENT_TYPE, user_id)
+            .await?
+        {
+            Some(content) => Ok(serde_json::from_value(content)?),
+            None => Ok(UserRefsEventContent::default()),
+        }
+    }
+
+    /// Sign the update of `ref_name` in the room to `sha` with the configured signing key
+    fn sign_ref_update(
+        &self,
+        room_id: &str,
+        ref_name: &str,
+        old_sha: &str,
+        sha: &str,
+    ) -> Result<RefSignature, Error> {
+        let pusher = self.matrix.user_id().unwrap().to_string();
+        let nonce = uuid::Uuid::new_v4().to_simple().to_string();
+
+        let payload = signing::payload(&pusher, room_id, &nonce, ref_name, old_sha, sha);
+        let (format, signature) = signing::sign(&self.git.repo.config()?, &payload)?;
+
+        Ok(RefSignature {
+            format,
+            old_sha: old_sha.to_owned(),
+            nonce,
+            signature,
+        })
+    }
+
+    /// Signed push certificate for the update of `ref_name`, the pusher is the
+    /// committer identity as with `git push --signed`
+    fn push_certificate(
+        &self,
+        ref_name: &str,
+        old_sha: &str,
+        sha: &str,
+    ) -> Result<PushCertEventContent, Error> {
+        let ident = self.git.repo.signature()?;
+        let offset = ident.when().offset_minutes();
+        let pusher = format!(
+            "{} <{}> {} {}{:02}{:02}",
+            ident.name().unwrap_or_default(),
+            ident.email().unwrap_or_default(),
+            ident.when().seconds(),
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        );
+        let nonce = uuid::Uuid::new_v4().to_simple().to_string();
+
+        let certificate = signing::push_certificate(
+            &pusher,
+            &self.matrix.room_id(),
+            &nonce,
+            ref_name,
+            old_sha,
+            sha,
+        );
+        let (format, signature) = signing::sign(&self.git.repo.config()?, &certificate)?;
+
+        Ok(PushCertEventContent {
+            format,
+            certificate,
+            signature,
+        })
+    }
+
+    /// Current protection rules and power levels of the room
+    ///
+    /// Rules sent by users who may not change them are left out, as when reading refs.
+    async fn authorization(&self) -> Result<Authorization, Error> {
+        let state = self.matrix.room_state().await?;
+        let find = |event_type: &str| {
+            state
+                .iter()
+                .find(|event| event["type"] == event_type && event["state_key"] == "")
+        };
+
+        let mut authorization = Authorization::default();
+        if let Some(event) = find(POWER_LEVELS_EVENT_TYPE) {
+            authorization.power_levels = serde_json::from_value(event["content"].clone())?;
+        }
+        if let Some(event) = find(PROTECTION_EVENT_TYPE) {
+            let sender = event["sender"].as_str().unwrap_or_default();
+            if authorization
+                .power_levels
+                .may_change_rules(sender, PROTECTION_EVENT_TYPE)
+            {
+                authorization.protection = serde_json::from_value(event["content"].clone())?;
+            }
+        }
+
+        Ok(authorization)
+    }
+
+    async fn check_protection(&self, dst: &str) -> Result<(), Error> {
+        let user_id = match self.matrix.user_id() {
+            Some(user_id) => user_id,
+            None => {
+                return Err(Error {
+                    message: "Pushing requires a Matrix session".to_owned(),
+                })
+            }
+        };
+
+        if let Some(owner) = user_refs::owner(dst) {
+            if owner != user_id.to_string() {
+                return Err(Error {
+                    message: format!("{} belongs to {}", dst, owner),
+                });
+            }
+        }
+
+        if !self.authorization().await?.authorizes(dst, &user_id) {
+            return Err(Error {
+                message: format!(
+                    "{} is protected and {} is not allowed to update it",
+                    dst, user_id
+                ),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a downloaded pack into the object database, decrypting it first if
+/// it was encrypted
+fn write_pack(
+    odb: &git2::Odb<'_>,
+    file: Vec<u8>,
+    encryption: Option<&attachment::EncryptedFile>,
+) -> Result<(), Error> {
+    let pack = match encryption {
+        Some(encryption) => attachment::decrypt(&file, encryption)?,
+        None => file,
+    };
+
+    let mut packwriter = odb.packwriter()?;
+    packwriter.write(&pack)?;
+    packwriter.commit()?;
+
+    Ok(())
+}
+
+/// Refs in the room state, given as events
+fn refs_from_state_events(
+    config: git2::Config,
+    room_id: String,
+    mut state: Vec<serde_json::Value>,
+) -> Result<VerifiedRefs, Error> {
+    // apply power levels before the rules they authorize, and rules before refs
+    state.sort_by_key(|event| {
+        if event["type"] == POWER_LEVELS_EVENT_TYPE {
+            0
+        } else if event["type"] == "org.gitmatrix.refs" || event["type"] == USER_REFS_EVENT_TYPE {
+            2
+        } else {
+            1
+        }
+    });
+
+    let mut ref_state = RefState::new(config, room_id, false);
+    for event in state {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        let state_key = event["state_key"].as_str().unwrap_or_default();
+        if event_type == POWER_LEVELS_EVENT_TYPE && state_key.is_empty() {
+            ref_state.authorization.power_levels =
+                serde_json::from_value(event["content"].clone())?;
+            continue;
+        }
+        let sender = match UserId::try_from(event["sender"].as_str().unwrap_or_default()) {
+            Ok(sender) => sender,
+            Err(_) => continue,
+        };
+        ref_state.apply((event_type, state_key, &sender), event["content"].clone());
+    }
+
+    Ok(ref_state.refs)
+}
+
+/// Refs built up by applying `org.gitmatrix.refs` events in order, with the
+/// rules that authorize and verify them
+struct RefState {
+    config: git2::Config,
+    room_id: String,
+    /// Whether updates are replayed in order, so that the previous commit of
+    /// each update is known
+    replayed: bool,
+    refs: VerifiedRefs,
+    authorization: Authorization,
+    trusted_keys: TrustedKeysEventContent,
+}
+
+impl RefState {
+    fn new(config: git2::Config, room_id: String, replayed: bool) -> Self {
+        Self {
+            config,
+            room_id,
+            replayed,
+            refs: HashMap::new(),
+            authorization: Authorization::default(),
+            trusted_keys: TrustedKeysEventContent::default(),
+        }
+    }
+
+    /// Apply a custom state event, given as type, state key and sender
+    fn apply(
+        &mut self,
+        (event_type, state_key, sender): (&str, &str, &UserId),
+        content: serde_json::Value,
+    ) {
+        match event_type {
+            // personal refs are only read from the owner's user refs event
+            "org.gitmatrix.refs" if user_refs::owner(state_key).is_none() => {
+                if let Ok(git_ref) = serde_json::from_value::<RefEventContent>(content) {
+                    self.apply_ref(state_key, sender, git_ref);
+                }
+            }
+            USER_REFS_EVENT_TYPE if state_key == sender.to_string() => {
+                let user_refs = match serde_json::from_value::<UserRefsEventContent>(content) {
+                    Ok(user_refs) => user_refs,
+                    Err(_) => return,
+                };
+                let owned =
+                    |ref_name: &str| user_refs::owner(ref_name).as_deref() == Some(state_key);
+                // the event has all of the user's refs, only the moved ones are updates
+                for (ref_name, git_ref) in user_refs
+                    .refs
+                    .iter()
+                    .filter(|(ref_name, _)| owned(ref_name))
+                {
+                    match self.refs.get(ref_name) {
+                        Some((sha, _)) if *sha == git_ref.sha => (),
+                        _ => self.apply_ref(ref_name, sender, git_ref.clone()),
+                    }
+                }
+                self.refs.retain(|ref_name, _| {
+                    !owned(ref_name) || user_refs.refs.contains_key(ref_name)
+                });
+            }
+            // the rules are read from the events with empty state key, sent by
+            // maintainers
+            PROTECTION_EVENT_TYPE | TRUSTED_KEYS_EVENT_TYPE
+                if !state_key.is_empty()
+                    || !self
+                        .authorization
+                        .power_levels
+                        .may_change_rules(&sender.to_string(), event_type) => {}
+            PROTECTION_EVENT_TYPE => {
+                if let Ok(protection) = serde_json::from_value::<ProtectionEventContent>(content) {
+                    self.authorization.protection = protection;
+                }
+            }
+            TRUSTED_KEYS_EVENT_TYPE => {
+                if let Ok(trusted_keys) = serde_json::from_value(content) {
+                    self.trusted_keys = trusted_keys;
                 }
             }
+            _ => (),
+        }
+    }
+
+    fn apply_ref(&mut self, ref_name: &str, sender: &UserId, git_ref: RefEventContent) {
+        if !self.authorization.authorizes(ref_name, sender) {
+            return;
+        }
+        let verification = self.verify(ref_name, sender, &git_ref);
+        if self.trusted_keys.required && verification != Verification::Verified {
+            return;
         }
+        self.refs
+            .insert(ref_name.to_owned(), (git_ref.sha, verification));
+    }
+
+    fn verify(&self, ref_name: &str, sender: &UserId, git_ref: &RefEventContent) -> Verification {
+        if self.trusted_keys.keys.is_empty() && !self.trusted_keys.required {
+            return Verification::Unchecked;
+        }
+        let signature = match &git_ref.signature {
+            Some(signature) => signature,
+            None => return Verification::Unverified("unsigned".to_owned()),
+        };
+
+        // a signature only counts for the update it was made for, not for replays
+        if self.replayed {
+            let old_sha = match self.refs.get(ref_name) {
+                Some((sha, _)) => &sha[..],
+                None => ZERO_SHA,
+            };
+            if signature.old_sha != old_sha {
+                return Verification::Unverified(format!(
+                    "signed for an update from {}",
+                    signature.old_sha
+                ));
+            }
+        }
+
+        let payload = signing::payload(
+            &sender.to_string(),
+            &self.room_id,
+            &signature.nonce,
+            ref_name,
+            &signature.old_sha,
+            &git_ref.sha,
+        );
+        match signing::verify(
+            &self.config,
+            &self.trusted_keys,
+            &sender.to_string(),
+            &payload,
+            (&signature.format, &signature.signature),
+        ) {
+            Ok(()) => Verification::Verified,
+            Err(error) => Verification::Unverified(error.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(remote_url: &str) -> (Homeserver, String) {
+        match parse_remote_url(remote_url) {
+            Ok(parsed) => parsed,
+            Err(error) => panic!("{}: {}", remote_url, error.message),
+        }
+    }
+
+    fn error(remote_url: &str) -> String {
+        match parse_remote_url(remote_url) {
+            Ok(parsed) => panic!("{} was parsed as {:?}", remote_url, parsed),
+            Err(error) => error.message,
+        }
+    }
+
+    fn server_name(server_name: &str) -> Homeserver {
+        Homeserver::ServerName(server_name.to_owned())
+    }
+
+    #[test]
+    fn alias_local_part_is_on_the_server() {
+        assert_eq!(
+            parse("matrix://example.org/repo"),
+            (server_name("example.org"), "#repo:example.org".to_owned())
+        );
+    }
+
+    #[test]
+    fn explicit_homeserver_url_is_used_as_is() {
+        assert_eq!(
+            parse("https://matrix.example.org:8448/repo"),
+            (
+                Homeserver::Url("https://matrix.example.org:8448".to_owned()),
+                "#repo:matrix.example.org".to_owned()
+            )
+        );
+        assert_eq!(
+            parse("http://localhost/repo").0,
+            Homeserver::Url("http://localhost:80".to_owned())
+        );
+    }
+
+    #[test]
+    fn aliases_on_another_server() {
+        let expected = (server_name("example.org"), "#repo:example.com".to_owned());
+
+        assert_eq!(parse("matrix://example.org/#repo:example.com"), expected);
+        assert_eq!(
+            parse("matrix://example.org/%23repo%3Aexample.com"),
+            expected
+        );
+    }
+
+    #[test]
+    fn room_ids() {
+        assert_eq!(
+            parse("matrix://example.org/!abc:example.com"),
+            (server_name("example.org"), "!abc:example.com".to_owned())
+        );
+    }
+
+    #[test]
+    fn matrix_to_links() {
+        assert_eq!(
+            parse("https://matrix.to/#/#repo:example.org"),
+            (server_name("example.org"), "#repo:example.org".to_owned())
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/%23repo%3Aexample.org"),
+            (server_name("example.org"), "#repo:example.org".to_owned())
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/!abc:example.org/$event:example.org"),
+            (server_name("example.org"), "!abc:example.org".to_owned())
+        );
+    }
+
+    #[test]
+    fn matrix_to_links_with_via() {
+        assert_eq!(
+            parse("https://matrix.to/#/!abc:example.org?via=example.com&via=example.net"),
+            (server_name("example.com"), "!abc:example.org".to_owned())
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/!abc?via=example.com").0,
+            server_name("example.com")
+        );
+    }
+
+    #[test]
+    fn matrix_uris() {
+        assert_eq!(
+            parse("matrix:r/repo:example.org"),
+            (server_name("example.org"), "#repo:example.org".to_owned())
+        );
+        assert_eq!(
+            parse("matrix:roomid/abc:example.org"),
+            (server_name("example.org"), "!abc:example.org".to_owned())
+        );
+        assert_eq!(
+            parse("matrix:roomid/abc:example.org?via=example.com"),
+            (server_name("example.com"), "!abc:example.org".to_owned())
+        );
+    }
+
+    #[test]
+    fn rooms_without_server_are_rejected() {
+        assert_eq!(
+            error("matrix:roomid/abc"),
+            "Can't tell the server of !abc, add ?via=<server>"
+        );
+        assert_eq!(
+            error("https://matrix.to/#/!abc"),
+            "Can't tell the server of !abc, add ?via=<server>"
+        );
+    }
+
+    #[test]
+    fn matrix_to_links_to_other_entities_are_rejected() {
+        for remote_url in &[
+            "https://matrix.to/#/@alice:example.org",
+            "https://matrix.to/",
+        ] {
+            assert_eq!(error(remote_url), "matrix.to link doesn't point to a room");
+        }
+    }
+
+    #[test]
+    fn other_matrix_uri_types_are_rejected() {
+        for remote_url in &["matrix:u/alice:example.org", "matrix:roomalias"] {
+            assert_eq!(
+                error(remote_url),
+                "Only matrix:r/ and matrix:roomid/ URIs are supported"
+            );
+        }
+    }
 
-        Ok(refs)
+    #[test]
+    fn paths_with_several_segments_are_rejected() {
+        assert_eq!(
+            error("matrix://example.org/a/repo"),
+            "Invalid path length in URL"
+        );
     }
 }
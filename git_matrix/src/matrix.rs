@@ -1,59 +1,129 @@
 use futures_util::stream::TryStreamExt;
+use ruma_api::error::{FromHttpResponseError, ServerError};
 use ruma_client::{
     api::r0,
     events::EventType,
-    identifiers::{RoomAliasId, RoomId, UserId},
+    identifiers::{EventId, RoomAliasId, RoomId, RoomIdOrAliasId, UserId},
     HttpsClient,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
 
+use crate::credential::Credentials;
 use crate::error::Error;
 
 pub use ruma_client::Session;
 
 mod events;
 
+/// Send a request, refreshing the access token and sending it again if it expired
+///
+/// The request expression is evaluated again for the retry.
+macro_rules! request {
+    ($matrix:expr, $request:expr) => {
+        match $matrix.client().request($request).await {
+            Err(error) => match errcode(error) {
+                (Some(errcode), error) if errcode == "M_UNKNOWN_TOKEN" => {
+                    if $matrix.refresh().await {
+                        $matrix.client().request($request).await
+                    } else {
+                        Err(error)
+                    }
+                }
+                (_, error) => Err(error),
+            },
+            response => response,
+        }
+    };
+}
+
 pub struct Builder {
     url: String,
     room: String,
 }
 
+/// Initial state of a room created by `Builder::create`
+pub struct RoomSettings {
+    pub public: bool,
+    pub initial_state: Vec<serde_json::Value>,
+    pub power_levels: serde_json::Value,
+}
+
 impl Builder {
     pub fn new(url: String, room: String) -> Self {
         Builder { url, room }
     }
 
-    pub async fn guest(&self) -> Result<(Matrix, Session), Error> {
-        let client = create_client(&self.url, None)?;
-        let session = client.register_guest().await?;
+    /// Access the room as guest, reusing `session` if it's still valid
+    ///
+    /// A new guest is registered otherwise. If the homeserver doesn't allow guests
+    /// the room is accessed without session, which is limited to reading the state
+    /// and events of world_readable rooms. Returns the guest session used, if any.
+    pub async fn guest(
+        &self,
+        session: Option<Session>,
+    ) -> Result<(Matrix, Option<Session>), Error> {
+        let session = match session {
+            Some(session) if whoami(&self.url, &session.access_token).await.is_ok() => {
+                Some(session)
+            }
+            _ => create_client(&self.url, None)?.register_guest().await.ok(),
+        };
+        let client = create_client(&self.url, session.clone())?;
         let room_id = self.resolve_room_alias(&client).await?;
 
-        Ok((Matrix { client, room_id }, session))
+        Ok((Matrix::new(client, room_id, None), session))
     }
 
-    pub async fn session(
+    pub async fn session(self, credentials: &Credentials) -> Result<Matrix, Error> {
+        let client = create_client(&self.url, Some(credentials.session()?))?;
+
+        let room_id = self.resolve_room_alias(&client).await?;
+
+        Ok(Matrix::new(client, room_id, Some(credentials.clone())))
+    }
+
+    /// Create the room and publish its alias
+    pub async fn create(
         self,
-        username: &str,
-        access_token: String,
-        device_id: String,
+        credentials: &Credentials,
+        settings: RoomSettings,
     ) -> Result<Matrix, Error> {
-        let client = create_client(
-            &self.url,
-            Some(Session {
-                access_token,
-                user_id: UserId::try_from(username).unwrap(),
-                device_id,
-            }),
-        )?;
+        let client = create_client(&self.url, Some(credentials.session()?))?;
 
-        let room_id = self.resolve_room_alias(&client).await?;
+        let alias = RoomAliasId::try_from(&self.room[..])?;
+        let (preset, visibility) = if settings.public {
+            ("public_chat", "public")
+        } else {
+            ("private_chat", "private")
+        };
+        let response = client
+            .request(events::create_room::Request {
+                preset: preset.to_owned(),
+                room_alias_name: alias.alias().to_owned(),
+                name: alias.alias().to_owned(),
+                visibility: visibility.to_owned(),
+                initial_state: settings.initial_state,
+                power_level_content_override: settings.power_levels,
+            })
+            .await?;
 
-        Ok(Matrix { client, room_id })
+        Ok(Matrix::new(
+            client,
+            response.room_id,
+            Some(credentials.clone()),
+        ))
     }
 
     async fn resolve_room_alias(&self, client: &HttpsClient) -> Result<RoomId, Error> {
+        if self.room.starts_with('!') {
+            return Ok(RoomId::try_from(&self.room[..])?);
+        }
+
         let response = client
-            .request(r0::alias::get_alias::Request {
+            .request(events::alias::Request {
                 room_alias: RoomAliasId::try_from(&self.room[..])?,
             })
             .await?;
@@ -62,30 +132,218 @@ impl Builder {
     }
 }
 
+/// Client-server API base URL of the server, from `.well-known/matrix/client`
+///
+/// Falls back to `url` itself if the server doesn't delegate.
+pub async fn discover_homeserver(url: &str) -> Result<String, Error> {
+    let client = create_client(url, None)?;
+    match client
+        .request(events::discover_homeserver::Request {})
+        .await
+    {
+        Ok(response) => Ok(response
+            .homeserver
+            .base_url
+            .trim_end_matches('/')
+            .to_owned()),
+        Err(_) => Ok(url.to_owned()),
+    }
+}
+
+/// User and device the access token belongs to
+pub async fn whoami(url: &str, access_token: &str) -> Result<(String, Option<String>), Error> {
+    let client = create_client(url, None)?;
+    let response = client
+        .request(events::whoami::Request {
+            access_token: access_token.to_owned(),
+        })
+        .await?;
+
+    Ok((response.user_id.to_string(), response.device_id))
+}
+
+/// Login types the homeserver supports, e.g. `m.login.password` or `m.login.sso`
+pub async fn login_types(url: &str) -> Result<Vec<String>, Error> {
+    let client = create_client(url, None)?;
+    let response = client.request(events::login_types::Request {}).await?;
+
+    Ok(response
+        .flows
+        .into_iter()
+        .map(|flow| flow.login_type)
+        .collect())
+}
+
+/// Where to send the user's browser for SSO, the homeserver redirects back to
+/// `redirect_url` with a `loginToken` query parameter
+pub fn sso_redirect_url(url: &str, redirect_url: &str) -> Result<String, Error> {
+    let mut url: url::Url = format!(
+        "{}/_matrix/client/r0/login/sso/redirect",
+        url.trim_end_matches('/')
+    )
+    .parse()?;
+    url.query_pairs_mut()
+        .append_pair("redirectUrl", redirect_url);
+
+    Ok(url.into())
+}
+
+/// Log in with a password
+///
+/// With `refresh_token`, the homeserver may issue a refresh token and a short
+/// lived access token, only request it if the refresh token can be stored.
+pub async fn log_in(
+    url: &str,
+    user: String,
+    password: String,
+    device_display_name: &str,
+    refresh_token: bool,
+) -> Result<Credentials, Error> {
+    log_in_with(
+        url,
+        events::login::Request {
+            login_type: "m.login.password".to_owned(),
+            identifier: Some(json!({ "type": "m.id.user", "user": user })),
+            password: Some(password),
+            token: None,
+            initial_device_display_name: device_display_name.to_owned(),
+            refresh_token,
+        },
+    )
+    .await
+}
+
+/// Exchange a login token for a session, see `log_in` for `refresh_token`
+pub async fn log_in_with_token(
+    url: &str,
+    token: String,
+    device_display_name: &str,
+    refresh_token: bool,
+) -> Result<Credentials, Error> {
+    log_in_with(
+        url,
+        events::login::Request {
+            login_type: "m.login.token".to_owned(),
+            identifier: None,
+            password: None,
+            token: Some(token),
+            initial_device_display_name: device_display_name.to_owned(),
+            refresh_token,
+        },
+    )
+    .await
+}
+
+async fn log_in_with(url: &str, request: events::login::Request) -> Result<Credentials, Error> {
+    let client = create_client(url, None)?;
+    let response = client.request(request).await?;
+
+    Ok(Credentials {
+        homeserver_url: url.to_owned(),
+        user_id: response.user_id.to_string(),
+        device_id: response.device_id,
+        access_token: response.access_token,
+        refresh_token: response.refresh_token,
+    })
+}
+
+/// Exchange a refresh token for a new access token and, possibly, refresh token
+pub async fn refresh(url: &str, refresh_token: String) -> Result<(String, Option<String>), Error> {
+    let client = create_client(url, None)?;
+    let response = client
+        .request(events::refresh::Request { refresh_token })
+        .await?;
+
+    Ok((response.access_token, response.refresh_token))
+}
+
+/// Invalidate the session's access token
+pub async fn log_out(
+    url: &str,
+    username: &str,
+    access_token: String,
+    device_id: String,
+) -> Result<(), Error> {
+    let client = create_client(
+        url,
+        Some(Session {
+            access_token,
+            user_id: UserId::try_from(username)?,
+            device_id,
+        }),
+    )?;
+    client.request(r0::session::logout::Request {}).await?;
+
+    Ok(())
+}
+
 pub fn create_client(url: &str, session: Option<Session>) -> Result<HttpsClient, Error> {
     Ok(HttpsClient::https(url.parse()?, session))
 }
 
 #[derive(Clone)]
 pub struct Matrix {
-    client: HttpsClient,
+    client: Arc<Mutex<HttpsClient>>,
     room_id: RoomId,
+    /// Session of the client, to refresh once its access token expires
+    credentials: Arc<Mutex<Option<Credentials>>>,
 }
 
 impl Matrix {
+    fn new(client: HttpsClient, room_id: RoomId, credentials: Option<Credentials>) -> Self {
+        Self {
+            client: Arc::new(Mutex::new(client)),
+            room_id,
+            credentials: Arc::new(Mutex::new(credentials)),
+        }
+    }
+
+    fn client(&self) -> HttpsClient {
+        self.client.lock().unwrap().clone()
+    }
+
+    fn access_token(&self) -> Option<String> {
+        self.client().session().map(|session| session.access_token)
+    }
+
+    /// Replace the client's expired access token, `false` if the session has no
+    /// refresh token or refreshing failed
+    async fn refresh(&self) -> bool {
+        let mut credentials = match self.credentials.lock().unwrap().clone() {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        if credentials.refresh().await.is_err() {
+            return false;
+        }
+
+        let client = match credentials
+            .session()
+            .and_then(|session| create_client(&credentials.homeserver_url, Some(session)))
+        {
+            Ok(client) => client,
+            Err(_) => return false,
+        };
+        *self.client.lock().unwrap() = client;
+        *self.credentials.lock().unwrap() = Some(credentials);
+
+        true
+    }
+
     pub async fn send_custom_event(
         &self,
         event_type: &str,
         content: serde_json::Value,
     ) -> Result<(), ruma_client::Error> {
-        self.client
-            .request(events::custom::Request {
+        request!(
+            self,
+            events::custom::Request {
                 room_id: self.room_id.clone(),
                 event_type: EventType::Custom(event_type.to_owned()),
                 txn_id: uuid::Uuid::new_v4().to_simple().to_string(),
-                content,
-            })
-            .await?;
+                content: content.clone(),
+            }
+        )?;
 
         Ok(())
     }
@@ -96,14 +354,14 @@ impl Matrix {
         content_type: &str,
         file: Vec<u8>,
     ) -> Result<r0::media::create_content::Response, ruma_client::Error> {
-        let response = self
-            .client
-            .request(r0::media::create_content::Request {
+        let response = request!(
+            self,
+            r0::media::create_content::Request {
                 filename: Some(filename.to_owned()),
                 content_type: content_type.to_owned(),
-                file,
-            })
-            .await?;
+                file: file.clone(),
+            }
+        )?;
 
         Ok(response)
     }
@@ -113,37 +371,256 @@ impl Matrix {
         media_id: String,
         server_name: String,
     ) -> Result<r0::media::get_content::Response, ruma_client::Error> {
-        self.client
-            .request(r0::media::get_content::Request {
-                media_id,
-                server_name,
-            })
-            .await
+        request!(
+            self,
+            r0::media::get_content::Request {
+                media_id: media_id.clone(),
+                server_name: server_name.clone(),
+            }
+        )
     }
 
-    pub async fn send_state_event_for_key(
+    pub async fn send_state_event_for_key<T: Serialize>(
         &self,
         event_type: &str,
         state_key: &str,
-        data: crate::RefEventContent,
+        data: T,
     ) -> Result<r0::state::create_state_event_for_key::Response, ruma_client::Error> {
         let data = serde_json::to_value(data).unwrap();
-        let response = self
-            .client
-            .request(r0::state::create_state_event_for_key::Request {
+        let response = request!(
+            self,
+            r0::state::create_state_event_for_key::Request {
                 room_id: self.room_id.clone(),
                 event_type: EventType::Custom(event_type.to_owned()),
                 state_key: state_key.to_owned(),
-                data,
-            })
-            .await?;
+                data: data.clone(),
+            }
+        )?;
 
         Ok(response)
     }
 
+    /// Content of the current state event, `None` if the room doesn't have it
+    pub async fn get_state_event(
+        &self,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<Option<serde_json::Value>, ruma_client::Error> {
+        if self.access_token().is_none() {
+            let state = self.room_state().await?;
+            return Ok(state
+                .into_iter()
+                .find(|event| event["type"] == event_type && event["state_key"] == state_key)
+                .map(|event| event["content"].clone()));
+        }
+
+        let response = request!(
+            self,
+            r0::state::get_state_events_for_key::Request {
+                room_id: self.room_id.clone(),
+                event_type: EventType::Custom(event_type.to_owned()),
+                state_key: state_key.to_owned(),
+            }
+        );
+
+        match response {
+            Ok(response) => Ok(Some(response.content)),
+            Err(error) => match errcode(error) {
+                (Some(errcode), _) if errcode == "M_NOT_FOUND" => Ok(None),
+                (_, error) => Err(error),
+            },
+        }
+    }
+
+    /// All current state events of the room
+    pub async fn room_state(&self) -> Result<Vec<serde_json::Value>, ruma_client::Error> {
+        let response = request!(
+            self,
+            events::state::Request {
+                room_id: self.room_id.clone(),
+                access_token: self.access_token(),
+            }
+        )?;
+
+        Ok(response.room_state)
+    }
+
+    /// All events of `event_type` in the room, oldest first, read by paginating
+    /// backwards instead of syncing
+    pub async fn messages(
+        &self,
+        event_type: &str,
+    ) -> Result<Vec<serde_json::Value>, ruma_client::Error> {
+        let filter = json!({ "types": [event_type] }).to_string();
+        let mut room_events = Vec::new();
+        let mut from = None;
+        loop {
+            let response = request!(
+                self,
+                events::messages::Request {
+                    room_id: self.room_id.clone(),
+                    access_token: self.access_token(),
+                    from: from.clone(),
+                    dir: "b".to_owned(),
+                    filter: filter.clone(),
+                }
+            )?;
+            let done = response.chunk.is_empty() || response.end.is_none();
+            room_events.extend(response.chunk);
+            if done {
+                break;
+            }
+            from = response.end;
+        }
+        room_events.reverse();
+
+        Ok(room_events)
+    }
+
+    /// Membership of the session's user, `None` if they never were a member or
+    /// can't see the room's state without joining
+    pub async fn membership(&self) -> Result<Option<String>, ruma_client::Error> {
+        let user_id = match self.user_id() {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        match self
+            .get_state_event("m.room.member", &user_id.to_string())
+            .await
+        {
+            Ok(content) => Ok(content.and_then(|content| {
+                content["membership"]
+                    .as_str()
+                    .map(|membership| membership.to_owned())
+            })),
+            Err(error) => match errcode(error) {
+                (Some(errcode), _) if errcode == "M_FORBIDDEN" => Ok(None),
+                (_, error) => Err(error),
+            },
+        }
+    }
+
+    /// Join the room, given by alias so that remote rooms can be joined through
+    /// the alias' server
+    pub async fn join(&self, room: RoomIdOrAliasId) -> Result<(), ruma_client::Error> {
+        request!(
+            self,
+            r0::membership::join_room_by_id_or_alias::Request {
+                room_id_or_alias: room.clone(),
+                third_party_signed: None,
+            }
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn invite(&self, user_id: UserId) -> Result<(), ruma_client::Error> {
+        request!(
+            self,
+            r0::membership::invite_user::Request {
+                room_id: self.room_id.clone(),
+                user_id: user_id.clone(),
+            }
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn kick(&self, user_id: UserId) -> Result<(), ruma_client::Error> {
+        request!(
+            self,
+            r0::membership::kick_user::Request {
+                reason: None,
+                room_id: self.room_id.clone(),
+                user_id: user_id.clone(),
+            }
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn redact(&self, event_id: EventId, reason: &str) -> Result<(), ruma_client::Error> {
+        request!(
+            self,
+            r0::redact::redact_event::Request {
+                event_id: event_id.clone(),
+                reason: Some(reason.to_owned()),
+                room_id: self.room_id.clone(),
+                txn_id: uuid::Uuid::new_v4().to_simple().to_string(),
+            }
+        )?;
+
+        Ok(())
+    }
+
+    pub fn room_id(&self) -> String {
+        self.room_id.to_string()
+    }
+
+    /// The same session, for another room
+    pub fn with_room(&self, room_id: RoomId) -> Matrix {
+        Matrix {
+            room_id,
+            ..self.clone()
+        }
+    }
+
+    /// Room that replaced this one, from its `m.room.tombstone` event
+    pub async fn replacement_room(&self) -> Result<Option<RoomId>, ruma_client::Error> {
+        Ok(self
+            .get_state_event("m.room.tombstone", "")
+            .await?
+            .and_then(|content| {
+                RoomId::try_from(content["replacement_room"].as_str().unwrap_or_default()).ok()
+            }))
+    }
+
+    /// Room this one was upgraded from, from its `m.room.create` event
+    pub async fn predecessor(&self) -> Result<Option<RoomId>, ruma_client::Error> {
+        Ok(self
+            .get_state_event("m.room.create", "")
+            .await?
+            .and_then(|content| {
+                RoomId::try_from(
+                    content["predecessor"]["room_id"]
+                        .as_str()
+                        .unwrap_or_default(),
+                )
+                .ok()
+            }))
+    }
+
+    /// Upgrade the room to `room_version`, returns the replacement room
+    pub async fn upgrade(&self, room_version: &str) -> Result<RoomId, ruma_client::Error> {
+        let response = request!(
+            self,
+            events::upgrade_room::Request {
+                room_id: self.room_id.clone(),
+                new_version: room_version.to_owned(),
+            }
+        )?;
+
+        Ok(response.replacement_room)
+    }
+
+    pub fn user_id(&self) -> Option<UserId> {
+        self.client().session().map(|session| session.user_id)
+    }
+
+    /// Whether the room is accessed with the credentials of a logged in user,
+    /// who joined it
+    ///
+    /// Guests don't join rooms, syncing doesn't return the room's events for them.
+    pub fn has_credentials(&self) -> bool {
+        self.credentials.lock().unwrap().is_some()
+    }
+
+    /// Sync the room's events of `types`, since the sync token if given
     pub async fn sync(
         &self,
         types: Vec<String>,
+        since: Option<String>,
     ) -> Result<r0::sync::sync_events::IncomingResponse, ruma_client::Error> {
         let filter =
             r0::sync::sync_events::Filter::FilterDefinition(r0::filter::FilterDefinition {
@@ -210,7 +687,49 @@ impl Matrix {
                 }),
             });
 
-        let mut sync_stream = Box::pin(self.client.sync(Some(filter), None, false));
-        Ok(sync_stream.try_next().await?.unwrap())
+        let sync = || async {
+            let mut sync_stream = Box::pin(self.client().sync(
+                Some(filter.clone()),
+                since.clone(),
+                false,
+            ));
+            Ok(sync_stream.try_next().await?.unwrap())
+        };
+        match sync().await {
+            Err(error) => match errcode(error) {
+                (Some(errcode), error) if errcode == "M_UNKNOWN_TOKEN" => {
+                    if self.refresh().await {
+                        sync().await
+                    } else {
+                        Err(error)
+                    }
+                }
+                (_, error) => Err(error),
+            },
+            response => response,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    errcode: String,
+}
+
+/// Splits off the Matrix error code of a request the server rejected
+pub fn errcode(error: ruma_client::Error) -> (Option<String>, ruma_client::Error) {
+    match error {
+        ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(error)) => {
+            let response = error.into_raw_reponse();
+            let errcode = serde_json::from_slice::<ErrorBody>(response.body())
+                .ok()
+                .map(|body| body.errcode);
+            let error = ServerError::new(response);
+            (
+                errcode,
+                ruma_client::Error::FromHttpResponse(FromHttpResponseError::Http(error)),
+            )
+        }
+        error => (None, error),
     }
 }
@@ -40,3 +40,337 @@ pub mod custom {
         }
     }
 }
+
+pub mod create_room {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::RoomId;
+
+    ruma_api! {
+        metadata {
+            description: "Create a new room with initial state.",
+            method: POST,
+            name: "create_room",
+            path: "/_matrix/client/r0/createRoom",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// Convenience parameter for setting various default state events.
+            pub preset: String,
+            /// The desired room alias local part.
+            pub room_alias_name: String,
+            /// The room name.
+            pub name: String,
+            /// Whether the room is shown in the published room list.
+            pub visibility: String,
+            /// State events to set in the new room.
+            pub initial_state: Vec<serde_json::Value>,
+            /// Content merged into the default power levels.
+            pub power_level_content_override: serde_json::Value,
+        }
+
+        response {
+            /// The created room's ID.
+            pub room_id: RoomId,
+        }
+    }
+}
+
+pub mod state {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::RoomId;
+
+    ruma_api! {
+        metadata {
+            description: "Get the state events of a room as raw JSON.",
+            method: GET,
+            name: "get_state_events",
+            path: "/_matrix/client/r0/rooms/:room_id/state",
+            rate_limited: false,
+            // the token is passed explicitly, world_readable rooms can be read without one
+            requires_authentication: false,
+        }
+
+        request {
+            /// The room to look up the state for.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+            /// The access token, if any.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub access_token: Option<String>,
+        }
+
+        response {
+            /// The current state of the room.
+            #[ruma_api(body)]
+            pub room_state: Vec<serde_json::Value>,
+        }
+    }
+}
+
+pub mod discover_homeserver {
+    use ruma_api::ruma_api;
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata {
+            description: "Get discovery information about the domain.",
+            method: GET,
+            name: "client_well_known",
+            path: "/.well-known/matrix/client",
+            rate_limited: false,
+            requires_authentication: false,
+        }
+
+        request {}
+
+        response {
+            /// Information about the homeserver to connect to.
+            #[serde(rename = "m.homeserver")]
+            pub homeserver: HomeserverInfo,
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct HomeserverInfo {
+        /// The base URL for the homeserver for client-server connections.
+        pub base_url: String,
+    }
+}
+
+pub mod whoami {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::UserId;
+
+    ruma_api! {
+        metadata {
+            description: "Get information about the owner of an access token.",
+            method: GET,
+            name: "whoami",
+            path: "/_matrix/client/r0/account/whoami",
+            rate_limited: true,
+            // the token is passed explicitly, a client doesn't have a session before
+            // knowing the user ID
+            requires_authentication: false,
+        }
+
+        request {
+            /// The access token to look up.
+            #[ruma_api(query)]
+            pub access_token: String,
+        }
+
+        response {
+            /// The user ID that owns the access token.
+            pub user_id: UserId,
+            /// The device ID associated with the access token, if any.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub device_id: Option<String>,
+        }
+    }
+}
+
+pub mod login_types {
+    use ruma_api::ruma_api;
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata {
+            description: "Get the homeserver's supported login types.",
+            method: GET,
+            name: "get_login_types",
+            path: "/_matrix/client/r0/login",
+            rate_limited: true,
+            requires_authentication: false,
+        }
+
+        request {}
+
+        response {
+            /// The homeserver's supported login types.
+            pub flows: Vec<LoginFlow>,
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct LoginFlow {
+        /// The login type, e.g. `m.login.password` or `m.login.sso`.
+        #[serde(rename = "type")]
+        pub login_type: String,
+    }
+}
+
+pub mod login {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::UserId;
+
+    ruma_api! {
+        metadata {
+            description: "Log in with a password or a login token, asking for a refresh token.",
+            method: POST,
+            name: "login",
+            path: "/_matrix/client/r0/login",
+            rate_limited: true,
+            requires_authentication: false,
+        }
+
+        request {
+            /// `m.login.password` or `m.login.token`.
+            #[serde(rename = "type")]
+            pub login_type: String,
+            /// Identification information for the user, for password logins.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub identifier: Option<serde_json::Value>,
+            /// The user's password.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub password: Option<String>,
+            /// The login token, as received from an SSO redirect.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub token: Option<String>,
+            /// A display name to assign to the newly-created device.
+            pub initial_device_display_name: String,
+            /// Whether the client supports refresh tokens.
+            pub refresh_token: bool,
+        }
+
+        response {
+            /// The fully-qualified Matrix ID that has been registered.
+            pub user_id: UserId,
+            /// An access token for the account.
+            pub access_token: String,
+            /// ID of the logged-in device.
+            pub device_id: String,
+            /// A refresh token, if the access token expires.
+            pub refresh_token: Option<String>,
+        }
+    }
+}
+
+pub mod refresh {
+    use ruma_api::ruma_api;
+
+    ruma_api! {
+        metadata {
+            description: "Exchange a refresh token for a new access token.",
+            method: POST,
+            name: "refresh",
+            path: "/_matrix/client/v3/refresh",
+            rate_limited: true,
+            requires_authentication: false,
+        }
+
+        request {
+            /// The refresh token.
+            pub refresh_token: String,
+        }
+
+        response {
+            /// The new access token.
+            pub access_token: String,
+            /// The new refresh token, the old one stays valid if not set.
+            pub refresh_token: Option<String>,
+        }
+    }
+}
+
+pub mod alias {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::{RoomAliasId, RoomId};
+
+    ruma_api! {
+        metadata {
+            description: "Resolve a room alias to a room ID, without authentication.",
+            method: GET,
+            name: "get_alias",
+            path: "/_matrix/client/r0/directory/room/:room_alias",
+            rate_limited: false,
+            requires_authentication: false,
+        }
+
+        request {
+            /// The room alias.
+            #[ruma_api(path)]
+            pub room_alias: RoomAliasId,
+        }
+
+        response {
+            /// The room ID for this room alias.
+            pub room_id: RoomId,
+        }
+    }
+}
+
+pub mod messages {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::RoomId;
+
+    ruma_api! {
+        metadata {
+            description: "Get events of a room as raw JSON, paginating backwards.",
+            method: GET,
+            name: "get_message_events",
+            path: "/_matrix/client/r0/rooms/:room_id/messages",
+            rate_limited: false,
+            // the token is passed explicitly, world_readable rooms can be read without one
+            requires_authentication: false,
+        }
+
+        request {
+            /// The room to get events from.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+            /// The access token, if any.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub access_token: Option<String>,
+            /// The token to start returning events from, the end of the room if not set.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<String>,
+            /// Always `b`.
+            #[ruma_api(query)]
+            pub dir: String,
+            /// A JSON `RoomEventFilter` to filter returned events with.
+            #[ruma_api(query)]
+            pub filter: String,
+        }
+
+        response {
+            /// The events, newest first.
+            pub chunk: Vec<serde_json::Value>,
+            /// The token to continue paginating from, missing at the start of the room.
+            pub end: Option<String>,
+        }
+    }
+}
+
+pub mod upgrade_room {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::RoomId;
+
+    ruma_api! {
+        metadata {
+            description: "Upgrade a room to a new room version.",
+            method: POST,
+            name: "upgrade_room",
+            path: "/_matrix/client/r0/rooms/:room_id/upgrade",
+            rate_limited: false,
+            requires_authentication: true,
+        }
+
+        request {
+            /// The room to upgrade.
+            #[ruma_api(path)]
+            pub room_id: RoomId,
+            /// The room version of the new room.
+            pub new_version: String,
+        }
+
+        response {
+            /// The room that replaces the upgraded room.
+            pub replacement_room: RoomId,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Offline mirrors of a repository's room, for backups independent of the homeserver
+//!
+//! A mirror is a directory with
+//!
+//! ```text
+//! mirror.json       room ID and sync token of the last run
+//! state.json        current state events of the room
+//! packs.json        org.gitmatrix.pack events, oldest first
+//! packs/<server>_<media-id>
+//!                   packs as uploaded, encrypted packs stay encrypted
+//! ```
+//!
+//! Remotes with a `matrix::file://` URL fetch from a mirror directory instead
+//! of the homeserver.
+
+use ruma_client::events::collections::all::RoomEvent;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::{
+    git, refs_from_state_events, write_pack, GitMatrix, PackEventContent, RepoEventContent,
+    VerifiedRefs, PACK_EVENT_TYPE, REPO_EVENT_TYPE,
+};
+
+const MIRROR_FILE: &str = "mirror.json";
+const STATE_FILE: &str = "state.json";
+const PACKS_FILE: &str = "packs.json";
+const PACKS_DIR: &str = "packs";
+
+/// Content of `mirror.json`
+#[derive(Serialize, Deserialize)]
+struct MirrorInfo {
+    room_id: String,
+    /// Sync token to continue from, `None` if the mirror was made as guest
+    next_batch: Option<String>,
+}
+
+impl GitMatrix {
+    /// Download the room's new packs and its current state into `dir`
+    ///
+    /// Later runs only download the packs sent since the last one. Returns the
+    /// number of packs downloaded.
+    pub async fn mirror(&self, dir: &Path) -> Result<usize, Error> {
+        fs::create_dir_all(dir.join(PACKS_DIR))?;
+
+        let room_id = self.matrix.room_id();
+        let since = match read_json::<MirrorInfo>(&dir.join(MIRROR_FILE))? {
+            Some(info) if info.room_id != room_id => {
+                return Err(Error {
+                    message: format!(
+                        "{} mirrors {}, not {}",
+                        dir.display(),
+                        info.room_id,
+                        room_id
+                    ),
+                })
+            }
+            Some(info) => info.next_batch,
+            None => None,
+        };
+
+        let mut events: Vec<serde_json::Value> =
+            read_json(&dir.join(PACKS_FILE))?.unwrap_or_default();
+        let mut event_ids: HashSet<String> = events
+            .iter()
+            .filter_map(|event| event["event_id"].as_str().map(|id| id.to_owned()))
+            .collect();
+
+        let (new_events, next_batch) = self.pack_events_since(since).await?;
+        let mut downloaded = 0;
+        for event in new_events {
+            let event_id = event["event_id"].as_str().unwrap_or_default().to_owned();
+            if event_ids.contains(&event_id) {
+                continue;
+            }
+            let pack: PackEventContent = match serde_json::from_value(event["content"].clone()) {
+                Ok(pack) => pack,
+                // redacted by git matrix gc
+                Err(_) => continue,
+            };
+            fs::write(
+                pack_path(dir, &pack.content_uri)?,
+                self.download(&pack.content_uri).await?,
+            )?;
+            downloaded += 1;
+
+            event_ids.insert(event_id);
+            events.push(event);
+        }
+
+        // the packs first, so that an interrupted run is picked up again
+        write_json(&dir.join(PACKS_FILE), &events)?;
+        write_json(&dir.join(STATE_FILE), &self.matrix.room_state().await?)?;
+        write_json(
+            &dir.join(MIRROR_FILE),
+            &MirrorInfo {
+                room_id,
+                next_batch,
+            },
+        )?;
+
+        Ok(downloaded)
+    }
+
+    /// Pack events sent since the sync token, with the token to continue from
+    ///
+    /// All pack events of the room are read if there's no token, the timeline
+    /// since is incomplete, or the room isn't joined to sync it.
+    async fn pack_events_since(
+        &self,
+        since: Option<String>,
+    ) -> Result<(Vec<serde_json::Value>, Option<String>), Error> {
+        if !self.matrix.has_credentials() {
+            return Ok((self.matrix.messages(PACK_EVENT_TYPE).await?, None));
+        }
+
+        let response = self
+            .matrix
+            .sync(vec![PACK_EVENT_TYPE.to_owned()], since.clone())
+            .await?;
+
+        let mut limited = since.is_none();
+        let mut events = Vec::new();
+        for (_, room) in response.rooms.join {
+            limited |= room.timeline.limited;
+            for event in room.timeline.events {
+                if let Ok(RoomEvent::CustomRoom(event)) = event.into_result() {
+                    events.push(json!({
+                        "event_id": event.event_id.to_string(),
+                        "sender": event.sender.to_string(),
+                        "type": PACK_EVENT_TYPE,
+                        "content": event.content,
+                    }));
+                }
+            }
+        }
+        if limited {
+            events = self.matrix.messages(PACK_EVENT_TYPE).await?;
+        }
+
+        Ok((events, Some(response.next_batch)))
+    }
+}
+
+/// Mirror directory read as remote
+pub struct Mirror {
+    dir: PathBuf,
+    room_id: String,
+    git: git::Git,
+}
+
+impl Mirror {
+    /// Mirror of a `file://` remote URL, `None` for other URLs
+    pub fn open(url: &str) -> Result<Option<Self>, Error> {
+        let url: url::Url = match url.parse() {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        if url.scheme() != "file" {
+            return Ok(None);
+        }
+
+        let dir = url.to_file_path().map_err(|_| Error {
+            message: "Invalid path in URL".to_owned(),
+        })?;
+        let info: MirrorInfo = match read_json(&dir.join(MIRROR_FILE))? {
+            Some(info) => info,
+            None => {
+                return Err(Error {
+                    message: format!("{} is not a git-matrix mirror", dir.display()),
+                })
+            }
+        };
+
+        Ok(Some(Self {
+            dir,
+            room_id: info.room_id,
+            git: git::Git::new()?,
+        }))
+    }
+
+    /// Repository metadata, `None` if the room has no `org.gitmatrix.repo` event
+    pub fn repo(&self) -> Result<Option<RepoEventContent>, Error> {
+        match self
+            .state()?
+            .into_iter()
+            .find(|event| event["type"] == REPO_EVENT_TYPE && event["state_key"] == "")
+        {
+            Some(event) => Ok(Some(serde_json::from_value(event["content"].clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Refs in the mirrored state, verified as for guests
+    pub fn verified_refs(&self) -> Result<VerifiedRefs, Error> {
+        refs_from_state_events(self.git.repo.config()?, self.room_id.clone(), self.state()?)
+    }
+
+    /// Write the mirrored packs since the latest checkpoint into the object database
+    pub fn fetch(&self) -> Result<(), Error> {
+        let odb = self.git.repo.odb()?;
+        let events: Vec<serde_json::Value> =
+            read_json(&self.dir.join(PACKS_FILE))?.unwrap_or_default();
+
+        for event in events.into_iter().rev() {
+            let pack: PackEventContent = serde_json::from_value(event["content"].clone())?;
+            let file = fs::read(pack_path(&self.dir, &pack.content_uri)?)?;
+            write_pack(&odb, file, pack.encryption.as_ref())?;
+            if pack.checkpoint {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> Result<Vec<serde_json::Value>, Error> {
+        Ok(read_json(&self.dir.join(STATE_FILE))?.unwrap_or_default())
+    }
+}
+
+/// File of the pack uploaded as `content_uri`, `packs/<server>_<media-id>`
+fn pack_path(dir: &Path, content_uri: &str) -> Result<PathBuf, Error> {
+    let uri = url::Url::parse(content_uri)?;
+    let server_name = uri.host_str().unwrap_or_default();
+    let media_id = uri
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or_default();
+    if server_name.is_empty() || media_id.is_empty() || media_id.contains("..") {
+        return Err(Error {
+            message: format!("Invalid pack URI {}", content_uri),
+        });
+    }
+
+    Ok(dir
+        .join(PACKS_DIR)
+        .join(format!("{}_{}", server_name, media_id)))
+}
+
+/// Content of a JSON file, `None` if it doesn't exist
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    fs::write(path, serde_json::to_vec_pretty(value)?)?;
+    Ok(())
+}
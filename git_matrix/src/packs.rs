@@ -0,0 +1,142 @@
+//! Manifest of the room's packs, in `org.gitmatrix.packs` state events
+//!
+//! Each pusher keeps the packs they sent in the event under their user ID as
+//! state key, so that pushes of different users don't overwrite each other's
+//! entries. Packs have all objects of the commits they were sent for, their
+//! tips, so fetching a commit takes the download of a single pack found in
+//! the room state, instead of reading the timeline for every pack.
+
+use serde::{Deserialize, Serialize};
+
+use crate::attachment::{self, EncryptedFile};
+use crate::error::Error;
+use crate::user_refs::USER_REFS_EVENT_TYPE;
+use crate::{write_pack, GitMatrix};
+
+pub const PACKS_EVENT_TYPE: &str = "org.gitmatrix.packs";
+
+/// Content of an `org.gitmatrix.packs` state event
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PacksEventContent {
+    #[serde(default)]
+    pub packs: Vec<PackEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub content_uri: String,
+    /// Size of the uploaded file in bytes
+    pub size: u64,
+    /// Unpadded base64 of the SHA-256 hash of the uploaded file
+    pub sha256: String,
+    /// Commits the pack has all objects of
+    pub tips: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptedFile>,
+}
+
+impl GitMatrix {
+    /// Packs listed in the `org.gitmatrix.packs` events of all pushers
+    pub async fn pack_manifest(&self) -> Result<Vec<PackEntry>, Error> {
+        let mut packs = Vec::new();
+        for event in self.matrix.room_state().await? {
+            if event["type"] != PACKS_EVENT_TYPE {
+                continue;
+            }
+            if let Ok(content) =
+                serde_json::from_value::<PacksEventContent>(event["content"].clone())
+            {
+                packs.extend(content.packs);
+            }
+        }
+
+        Ok(packs)
+    }
+
+    /// Add the pack to the user's manifest, replacing all packs if `replace` is set
+    ///
+    /// Packs whose tips no ref points to anymore are dropped, refs that moved
+    /// back to one of those commits are fetched from the timeline.
+    pub async fn update_pack_manifest(&self, entry: PackEntry, replace: bool) -> Result<(), Error> {
+        let user_id = self.matrix.user_id().unwrap().to_string();
+
+        let mut packs = Vec::new();
+        if !replace {
+            let mut shas: Vec<String> = Vec::new();
+            for event in self.matrix.room_state().await? {
+                if event["type"] == "org.gitmatrix.refs" {
+                    shas.extend(event["content"]["sha"].as_str().map(|sha| sha.to_owned()));
+                } else if event["type"] == USER_REFS_EVENT_TYPE {
+                    if let Some(refs) = event["content"]["refs"].as_object() {
+                        shas.extend(
+                            refs.values()
+                                .filter_map(|git_ref| git_ref["sha"].as_str())
+                                .map(|sha| sha.to_owned()),
+                        );
+                    }
+                }
+            }
+            if let Some(content) = self
+                .matrix
+                .get_state_event(PACKS_EVENT_TYPE, &user_id)
+                .await?
+            {
+                packs = serde_json::from_value::<PacksEventContent>(content)?.packs;
+            }
+            packs.retain(|pack| pack.tips.iter().any(|tip| shas.contains(tip)));
+        }
+        packs.push(entry);
+
+        self.matrix
+            .send_state_event_for_key(PACKS_EVENT_TYPE, &user_id, PacksEventContent { packs })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Download the commits' objects, each with the smallest listed pack that
+    /// has it as tip
+    ///
+    /// Falls back to fetching the packs of the timeline for commits no listed
+    /// pack has, as for rooms without manifest.
+    pub async fn fetch_commits(&self, shas: &[String]) -> Result<(), Error> {
+        let odb = self.git.repo.odb()?;
+        let exists = |sha: &str| {
+            git2::Oid::from_str(sha)
+                .map(|oid| odb.exists(oid))
+                .unwrap_or(false)
+        };
+        if shas.iter().all(|sha| exists(sha)) {
+            return Ok(());
+        }
+
+        let manifest = self.pack_manifest().await?;
+        for sha in shas {
+            if exists(sha) {
+                continue;
+            }
+            let entry = match manifest
+                .iter()
+                .filter(|pack| pack.tips.contains(sha))
+                .min_by_key(|pack| pack.size)
+            {
+                Some(entry) => entry,
+                None => return self.fetch().await,
+            };
+
+            let file = self.download(&entry.content_uri).await?;
+            if file.len() as u64 != entry.size || attachment::hash(&file) != entry.sha256 {
+                return Err(Error {
+                    message: format!("The pack {} doesn't match its manifest", entry.content_uri),
+                });
+            }
+            write_pack(&odb, file, entry.encryption.as_ref())?;
+        }
+
+        if shas.iter().all(|sha| exists(sha)) {
+            Ok(())
+        } else {
+            self.fetch().await
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use ruma_client::identifiers::UserId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::collaborators::Role;
+
+pub const PROTECTION_EVENT_TYPE: &str = "org.gitmatrix.protection";
+pub const POWER_LEVELS_EVENT_TYPE: &str = "m.room.power_levels";
+
+/// Content of the `org.gitmatrix.protection` state event
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ProtectionEventContent {
+    #[serde(default)]
+    pub rules: Vec<ProtectionRule>,
+}
+
+/// Restricts who may update refs matching `pattern`
+///
+/// A sender is authorized if they are listed in `users` or their power level is at
+/// least `power_level`. A rule without either locks the matching refs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProtectionRule {
+    /// Ref name, `*` matches any sequence of characters
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_level: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+}
+
+impl ProtectionRule {
+    pub fn matches(&self, ref_name: &str) -> bool {
+        pattern_matches(&self.pattern, ref_name)
+    }
+
+    fn authorizes(&self, sender: &str, power_level: i64) -> bool {
+        if self.users.iter().any(|user| user == sender) {
+            return true;
+        }
+
+        match self.power_level {
+            Some(required) => power_level >= required,
+            None => false,
+        }
+    }
+}
+
+/// The parts of `m.room.power_levels` needed to authorize ref updates
+#[derive(Clone, Default, Deserialize)]
+pub struct PowerLevels {
+    #[serde(default)]
+    pub users: HashMap<String, i64>,
+    #[serde(default)]
+    pub users_default: i64,
+    #[serde(default)]
+    pub events: HashMap<String, i64>,
+}
+
+impl PowerLevels {
+    pub fn user_level(&self, user: &str) -> i64 {
+        *self.users.get(user).unwrap_or(&self.users_default)
+    }
+
+    /// Whether `sender` may change the rules in `event_type` events
+    ///
+    /// Rooms not created by git matrix init leave these events at the state
+    /// default, which writers have, so maintainers are required at least.
+    pub fn may_change_rules(&self, sender: &str, event_type: &str) -> bool {
+        let required = self.events.get(event_type).copied().unwrap_or_default();
+        self.user_level(sender) >= required.max(Role::Maintainer.power_level())
+    }
+}
+
+/// Protection rules and power levels as of a point in the room's history
+#[derive(Clone, Default)]
+pub struct Authorization {
+    pub protection: ProtectionEventContent,
+    pub power_levels: PowerLevels,
+}
+
+impl Authorization {
+    /// Whether `sender` may update `ref_name`, every matching rule has to authorize
+    pub fn authorizes(&self, ref_name: &str, sender: &UserId) -> bool {
+        let sender = sender.to_string();
+        let power_level = self.power_levels.user_level(&sender);
+
+        self.protection
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(ref_name))
+            .all(|rule| rule.authorizes(&sender, power_level))
+    }
+}
+
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(index) => {
+            let (prefix, rest) = (&pattern[..index], &pattern[index + 1..]);
+            if !name.starts_with(prefix) {
+                return false;
+            }
+            let name = &name[prefix.len()..];
+            (0..=name.len())
+                .filter(|&split| name.is_char_boundary(split))
+                .any(|split| pattern_matches(rest, &name[split..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn rule(pattern: &str, power_level: Option<i64>, users: &[&str]) -> ProtectionRule {
+        ProtectionRule {
+            pattern: pattern.to_owned(),
+            power_level,
+            users: users.iter().map(|user| (*user).to_owned()).collect(),
+        }
+    }
+
+    fn authorization(rules: Vec<ProtectionRule>) -> Authorization {
+        let mut power_levels = PowerLevels::default();
+        power_levels
+            .users
+            .insert("@maintainer:example.org".to_owned(), 100);
+        power_levels
+            .users
+            .insert("@writer:example.org".to_owned(), 50);
+
+        Authorization {
+            protection: ProtectionEventContent { rules },
+            power_levels,
+        }
+    }
+
+    fn user(user_id: &str) -> UserId {
+        UserId::try_from(user_id).unwrap()
+    }
+
+    #[test]
+    fn pattern_without_wildcard_matches_exactly() {
+        assert!(pattern_matches("refs/heads/main", "refs/heads/main"));
+        assert!(!pattern_matches("refs/heads/main", "refs/heads/main2"));
+        assert!(!pattern_matches("refs/heads/main", "refs/heads"));
+    }
+
+    #[test]
+    fn wildcard_matches_any_sequence() {
+        assert!(pattern_matches("refs/tags/*", "refs/tags/v1.0"));
+        assert!(pattern_matches("refs/tags/*", "refs/tags/"));
+        assert!(pattern_matches("refs/heads/*/main", "refs/heads/a/b/main"));
+        assert!(pattern_matches("*", "refs/heads/main"));
+        assert!(pattern_matches("refs/*/release-*", "refs/heads/release-1"));
+        assert!(!pattern_matches("refs/tags/*", "refs/heads/main"));
+        assert!(!pattern_matches("refs/heads/*/main", "refs/heads/a/next"));
+    }
+
+    #[test]
+    fn wildcard_matches_multibyte_names() {
+        assert!(pattern_matches("refs/heads/*ü", "refs/heads/menü"));
+        assert!(!pattern_matches("refs/heads/*ü", "refs/heads/menu"));
+    }
+
+    #[test]
+    fn refs_without_rules_are_authorized() {
+        let authorization = authorization(vec![rule("refs/heads/main", Some(100), &[])]);
+
+        assert!(authorization.authorizes("refs/heads/topic", &user("@reader:example.org")));
+    }
+
+    #[test]
+    fn rules_authorize_power_level_or_listed_users() {
+        let authorization = authorization(vec![rule(
+            "refs/heads/main",
+            Some(100),
+            &["@writer:example.org"],
+        )]);
+
+        assert!(authorization.authorizes("refs/heads/main", &user("@maintainer:example.org")));
+        assert!(authorization.authorizes("refs/heads/main", &user("@writer:example.org")));
+        assert!(!authorization.authorizes("refs/heads/main", &user("@reader:example.org")));
+    }
+
+    #[test]
+    fn rules_without_power_level_or_users_lock_refs() {
+        let authorization = authorization(vec![rule("refs/tags/*", None, &[])]);
+
+        assert!(!authorization.authorizes("refs/tags/v1.0", &user("@maintainer:example.org")));
+    }
+
+    #[test]
+    fn every_matching_rule_has_to_authorize() {
+        let authorization = authorization(vec![
+            rule("refs/heads/*", Some(50), &[]),
+            rule("refs/heads/main", Some(100), &[]),
+        ]);
+
+        assert!(authorization.authorizes("refs/heads/topic", &user("@writer:example.org")));
+        assert!(!authorization.authorizes("refs/heads/main", &user("@writer:example.org")));
+        assert!(authorization.authorizes("refs/heads/main", &user("@maintainer:example.org")));
+    }
+
+    #[test]
+    fn rules_are_changed_by_maintainers_at_least() {
+        let mut power_levels = authorization(Vec::new()).power_levels;
+
+        assert!(power_levels.may_change_rules("@maintainer:example.org", PROTECTION_EVENT_TYPE));
+        assert!(!power_levels.may_change_rules("@writer:example.org", PROTECTION_EVENT_TYPE));
+
+        power_levels
+            .events
+            .insert(PROTECTION_EVENT_TYPE.to_owned(), 50);
+        assert!(!power_levels.may_change_rules("@writer:example.org", PROTECTION_EVENT_TYPE));
+
+        power_levels
+            .events
+            .insert(PROTECTION_EVENT_TYPE.to_owned(), 150);
+        assert!(!power_levels.may_change_rules("@maintainer:example.org", PROTECTION_EVENT_TYPE));
+    }
+
+    #[test]
+    fn users_default_applies_to_unlisted_users() {
+        let mut authorization = authorization(vec![rule("refs/heads/main", Some(50), &[])]);
+        authorization.power_levels.users_default = 50;
+
+        assert!(authorization.authorizes("refs/heads/main", &user("@reader:example.org")));
+    }
+}
@@ -0,0 +1,395 @@
+//! Ref updates signed with the pusher's OpenPGP or SSH key
+//!
+//! Like the certificates of `git push --signed`, the signed payload names the
+//! pusher, the room, a nonce and the ref's old and new commit. Signatures are
+//! checked against the keys the room trusts for the sender, which are listed in
+//! the `org.gitmatrix.trusted_keys` state event.
+//!
+//! `git push --signed` certificates are stored as `org.gitmatrix.push_cert`
+//! state events, with the name of the ref they update as state key.
+
+use git2::Config;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::error::Error;
+use crate::user_refs::{self, USER_REFS_EVENT_TYPE};
+use crate::GitMatrix;
+
+pub const TRUSTED_KEYS_EVENT_TYPE: &str = "org.gitmatrix.trusted_keys";
+pub const PUSH_CERT_EVENT_TYPE: &str = "org.gitmatrix.push_cert";
+
+/// Old commit of a ref that didn't exist before the update
+pub const ZERO_SHA: &str = "0000000000000000000000000000000000000000";
+
+/// Content of the `org.gitmatrix.trusted_keys` state event
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TrustedKeysEventContent {
+    #[serde(default)]
+    pub keys: Vec<TrustedKey>,
+    /// Ignore ref updates that aren't signed with a trusted key of their sender
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+    pub user_id: String,
+    /// `openpgp` or `ssh`, as in `gpg.format`
+    pub format: String,
+    /// Fingerprint of an OpenPGP key, or SSH public key as in an allowed signers file
+    pub key: String,
+}
+
+/// Signature of a ref update, part of the `org.gitmatrix.refs` event
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RefSignature {
+    pub format: String,
+    pub old_sha: String,
+    pub nonce: String,
+    /// Armored signature of the payload
+    pub signature: String,
+}
+
+/// Content of the `org.gitmatrix.push_cert` state event
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PushCertEventContent {
+    pub format: String,
+    /// Certificate in the format of `git push --signed`, without signature
+    pub certificate: String,
+    /// Armored signature of the certificate
+    pub signature: String,
+}
+
+/// Push certificate of a ref's last update, with the user who sent it
+pub struct PushCertificate {
+    pub sender: String,
+    pub content: PushCertEventContent,
+}
+
+/// Result of checking the signature of a ref's last update
+#[derive(Clone, PartialEq)]
+pub enum Verification {
+    /// Signed with a trusted key of the sender
+    Verified,
+    /// Unsigned or not signed with a trusted key, with the reason
+    Unverified(String),
+    /// The room doesn't list trusted keys
+    Unchecked,
+}
+
+/// Payload signed for updating `ref_name` from `old_sha` to `new_sha`
+pub fn payload(
+    pusher: &str,
+    room_id: &str,
+    nonce: &str,
+    ref_name: &str,
+    old_sha: &str,
+    new_sha: &str,
+) -> String {
+    format!(
+        "certificate version 0.1\npusher {}\nroom {}\nnonce {}\n\n{} {} {}\n",
+        pusher, room_id, nonce, old_sha, new_sha, ref_name
+    )
+}
+
+/// Push certificate for updating `ref_name` from `old_sha` to `new_sha`, as git
+/// creates them for `git push --signed`
+pub fn push_certificate(
+    pusher: &str,
+    pushee: &str,
+    nonce: &str,
+    ref_name: &str,
+    old_sha: &str,
+    new_sha: &str,
+) -> String {
+    format!(
+        "certificate version 0.1\npusher {}\npushee {}\nnonce {}\n\n{} {} {}\n",
+        pusher, pushee, nonce, old_sha, new_sha, ref_name
+    )
+}
+
+/// Sign the payload with `user.signingkey`, in the format set by `gpg.format`
+///
+/// Returns the format and the armored signature.
+pub fn sign(config: &Config, payload: &str) -> Result<(String, String), Error> {
+    let format = config
+        .get_string("gpg.format")
+        .unwrap_or_else(|_| "openpgp".to_owned());
+    let signing_key = config.get_string("user.signingkey").ok();
+
+    let output = match (&format[..], signing_key) {
+        ("openpgp", Some(signing_key)) => run(
+            &program(config, "openpgp", "gpg"),
+            &["--status-fd=2", "-bsau", &signing_key],
+            payload,
+        )?,
+        ("openpgp", None) => run(
+            &program(config, "openpgp", "gpg"),
+            &["--status-fd=2", "-bsa"],
+            payload,
+        )?,
+        ("ssh", Some(signing_key)) => {
+            let program = program(config, "ssh", "ssh-keygen");
+            if Path::new(&signing_key).exists() {
+                run(
+                    &program,
+                    &["-Y", "sign", "-n", "git", "-f", &signing_key],
+                    payload,
+                )?
+            } else {
+                // a literal public key, the private key has to be in the agent
+                let key_file = TempFile::new(signing_key.trim_start_matches("key::"))?;
+                run(
+                    &program,
+                    &["-Y", "sign", "-n", "git", "-U", "-f", key_file.path()],
+                    payload,
+                )?
+            }
+        }
+        ("ssh", None) => {
+            return Err(Error {
+                message: "Signing with SSH requires user.signingkey".to_owned(),
+            })
+        }
+        (format, _) => {
+            return Err(Error {
+                message: format!(
+                    "Signing ref updates with gpg.format {} is not supported",
+                    format
+                ),
+            })
+        }
+    };
+
+    if !output.status.success() {
+        return Err(Error {
+            message: format!(
+                "Signing the ref update failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+
+    Ok((format, String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Check the signature against the trusted keys of `sender`
+///
+/// OpenPGP keys have to be in the local keyring.
+pub fn verify(
+    config: &Config,
+    trusted_keys: &TrustedKeysEventContent,
+    sender: &str,
+    payload: &str,
+    (format, signature): (&str, &str),
+) -> Result<(), Error> {
+    let keys: Vec<&TrustedKey> = trusted_keys
+        .keys
+        .iter()
+        .filter(|key| key.user_id == sender && key.format == format)
+        .collect();
+    if keys.is_empty() {
+        return Err(Error {
+            message: format!("{} has no trusted {} key", sender, format),
+        });
+    }
+
+    let signature_file = TempFile::new(signature)?;
+    let verified = match format {
+        "openpgp" => {
+            let output = run(
+                &program(config, "openpgp", "gpg"),
+                &["--status-fd=1", "--verify", signature_file.path(), "-"],
+                payload,
+            )?;
+            // [GNUPG:] VALIDSIG <fingerprint> ... <primary key fingerprint>
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            stdout
+                .lines()
+                .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+                .flat_map(|line| line.split(' '))
+                .any(|fingerprint| {
+                    keys.iter().any(|key| {
+                        key.key.replace(' ', "").to_uppercase() == fingerprint.to_uppercase()
+                    })
+                })
+        }
+        "ssh" => {
+            let allowed_signers: String = keys
+                .iter()
+                .map(|key| format!("{} {}\n", sender, key.key))
+                .collect();
+            let allowed_signers_file = TempFile::new(&allowed_signers)?;
+            let output = run(
+                &program(config, "ssh", "ssh-keygen"),
+                &[
+                    "-Y",
+                    "verify",
+                    "-n",
+                    "git",
+                    "-f",
+                    allowed_signers_file.path(),
+                    "-I",
+                    sender,
+                    "-s",
+                    signature_file.path(),
+                ],
+                payload,
+            )?;
+            output.status.success()
+        }
+        format => {
+            return Err(Error {
+                message: format!("Unsupported signature format {}", format),
+            })
+        }
+    };
+
+    if !verified {
+        return Err(Error {
+            message: format!("not signed with a trusted key of {}", sender),
+        });
+    }
+
+    Ok(())
+}
+
+impl GitMatrix {
+    pub async fn trusted_keys(&self) -> Result<TrustedKeysEventContent, Error> {
+        match self
+            .matrix
+            .get_state_event(TRUSTED_KEYS_EVENT_TYPE, "")
+            .await?
+        {
+            Some(content) => Ok(serde_json::from_value(content)?),
+            None => Ok(TrustedKeysEventContent::default()),
+        }
+    }
+
+    pub async fn set_trusted_keys(
+        &self,
+        trusted_keys: TrustedKeysEventContent,
+    ) -> Result<(), Error> {
+        self.matrix
+            .send_state_event_for_key(TRUSTED_KEYS_EVENT_TYPE, "", trusted_keys)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Push certificate of the last update of `ref_name`, checked against the
+    /// ref's current commit and the trusted keys of its sender
+    pub async fn verify_push(&self, ref_name: &str) -> Result<PushCertificate, Error> {
+        let state = self.matrix.room_state().await?;
+        let find = |event_type: &str, state_key: &str| {
+            state
+                .iter()
+                .find(|event| event["type"] == event_type && event["state_key"] == state_key)
+        };
+
+        // personal refs are part of their owner's user refs event
+        let git_ref = match user_refs::owner(ref_name) {
+            Some(owner) => find(USER_REFS_EVENT_TYPE, &owner).map(|event| {
+                json!({
+                    "sender": event["sender"],
+                    "content": event["content"]["refs"][ref_name],
+                })
+            }),
+            None => find("org.gitmatrix.refs", ref_name).cloned(),
+        };
+        let (git_ref, push_cert) = match (git_ref, find(PUSH_CERT_EVENT_TYPE, ref_name)) {
+            (Some(git_ref), Some(push_cert)) => (git_ref, push_cert),
+            _ => {
+                return Err(Error {
+                    message: format!("{} has no push certificate", ref_name),
+                })
+            }
+        };
+        let certificate = PushCertificate {
+            sender: push_cert["sender"].as_str().unwrap_or_default().to_owned(),
+            content: serde_json::from_value(push_cert["content"].clone())?,
+        };
+
+        let update = format!(
+            " {} {}",
+            git_ref["content"]["sha"].as_str().unwrap_or_default(),
+            ref_name
+        );
+        if git_ref["sender"] != push_cert["sender"]
+            || !certificate
+                .content
+                .certificate
+                .lines()
+                .any(|line| line.ends_with(&update))
+        {
+            return Err(Error {
+                message: format!(
+                    "The push certificate is not for the current commit of {}",
+                    ref_name
+                ),
+            });
+        }
+
+        verify(
+            &self.git.repo.config()?,
+            &self.trusted_keys().await?,
+            &certificate.sender,
+            &certificate.content.certificate,
+            (&certificate.content.format, &certificate.content.signature),
+        )?;
+
+        Ok(certificate)
+    }
+}
+
+/// `gpg.<format>.program`, falling back to `gpg.program` for OpenPGP as git does
+fn program(config: &Config, format: &str, default: &str) -> String {
+    config
+        .get_string(&format!("gpg.{}.program", format))
+        .or_else(|error| match format {
+            "openpgp" => config.get_string("gpg.program"),
+            _ => Err(error),
+        })
+        .unwrap_or_else(|_| default.to_owned())
+}
+
+fn run(program: &str, args: &[&str], input: &str) -> Result<Output, Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+
+    Ok(child.wait_with_output()?)
+}
+
+/// File handed to gpg or ssh-keygen, removed when dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(content: &str) -> Result<Self, Error> {
+        let path =
+            std::env::temp_dir().join(format!("git-matrix-{}", uuid::Uuid::new_v4().to_simple()));
+        std::fs::write(&path, content)?;
+
+        Ok(Self { path })
+    }
+
+    fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,116 @@
+//! Personal refs under `refs/users/<user-id>/`
+//!
+//! Each user's personal refs are stored together in an `org.gitmatrix.user_refs`
+//! state event whose state key is their user ID. Homeservers reject state keys
+//! starting with `@` unless they are the sender's user ID, so only the owner can
+//! move them. Git doesn't allow `:` in ref names, so it is written as `%3A` in
+//! the user ID part of the ref name.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::RefEventContent;
+
+pub const USER_REFS_PREFIX: &str = "refs/users/";
+
+pub const USER_REFS_EVENT_TYPE: &str = "org.gitmatrix.user_refs";
+
+/// Content of the `org.gitmatrix.user_refs` state event of a user
+#[derive(Default, Serialize, Deserialize)]
+pub struct UserRefsEventContent {
+    /// Refs by their full name
+    #[serde(default)]
+    pub refs: BTreeMap<String, RefEventContent>,
+}
+
+/// Name of the user's personal ref `name`
+pub fn ref_name(user_id: &str, name: &str) -> String {
+    format!(
+        "{}{}/{}",
+        USER_REFS_PREFIX,
+        user_id.replace(':', "%3A"),
+        name
+    )
+}
+
+/// Owner of a personal ref
+pub fn owner(ref_name: &str) -> Option<String> {
+    split_ref_name(ref_name).map(|(user_id, _)| user_id)
+}
+
+fn split_ref_name(ref_name: &str) -> Option<(String, &str)> {
+    if !ref_name.starts_with(USER_REFS_PREFIX) {
+        return None;
+    }
+
+    let rest = &ref_name[USER_REFS_PREFIX.len()..];
+    let separator = rest.find('/')?;
+    let user_id = rest[..separator].replace("%3A", ":");
+    if !user_id.starts_with('@') || !user_id.contains(':') || separator + 1 == rest.len() {
+        return None;
+    }
+
+    Some((user_id, &rest[separator + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ref_name_escapes_colon() {
+        assert_eq!(
+            ref_name("@alice:example.org", "heads/topic"),
+            "refs/users/@alice%3Aexample.org/heads/topic"
+        );
+    }
+
+    #[test]
+    fn owner_round_trips_ref_name() {
+        for user_id in &["@alice:example.org", "@bob:localhost:8448"] {
+            assert_eq!(
+                owner(&ref_name(user_id, "heads/topic")),
+                Some((*user_id).to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn split_ref_name_returns_name() {
+        assert_eq!(
+            split_ref_name(&ref_name("@alice:example.org", "heads/a/b")),
+            Some(("@alice:example.org".to_owned(), "heads/a/b"))
+        );
+    }
+
+    #[test]
+    fn other_refs_have_no_owner() {
+        assert_eq!(owner("refs/heads/main"), None);
+        assert_eq!(owner("refs/users/alice/heads/topic"), None);
+        assert_eq!(owner("refs/users/@alice/heads/topic"), None);
+        assert_eq!(owner("refs/users/@alice%3Aexample.org"), None);
+        assert_eq!(owner("refs/users/@alice%3Aexample.org/"), None);
+    }
+
+    #[test]
+    fn user_refs_event_content_round_trips() {
+        let mut content = UserRefsEventContent::default();
+        content.refs.insert(
+            ref_name("@alice:example.org", "heads/topic"),
+            serde_json::from_value(serde_json::json!({
+                "sha": "0123456789012345678901234567890123456789",
+            }))
+            .unwrap(),
+        );
+
+        let json = serde_json::to_value(&content).unwrap();
+        let parsed: UserRefsEventContent = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+        assert!(
+            serde_json::from_value::<UserRefsEventContent>(serde_json::json!({}))
+                .unwrap()
+                .refs
+                .is_empty()
+        );
+    }
+}
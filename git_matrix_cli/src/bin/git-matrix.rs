@@ -1,32 +1,717 @@
 #[macro_use]
 extern crate text_io;
 
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::Command;
+
+use git_matrix::bundle::Bundle;
+use git_matrix::collaborators::Role;
+use git_matrix::credential::Credentials;
+use git_matrix::error::Error;
 use git_matrix::git;
 use git_matrix::matrix;
+use git_matrix::signing::TrustedKey;
+use git_matrix::{GitMatrix, GitMatrixBuilder, RepoEventContent};
+
+const DEVICE_DISPLAY_NAME: &str = "git-matrix";
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|command| &command[..]) {
+        None => login(&[]).await,
+        Some("login") => login(&args[2..]).await,
+        Some("init") => init(&args[2..]).await,
+        Some("info") => info(args.get(2).map(|remote| &remote[..]).unwrap_or("origin")).await,
+        Some("collaborators") => collaborators(&args[2..]).await,
+        Some("trusted-keys") => trusted_keys(&args[2..]).await,
+        Some("verify-push") => verify_push(&args[2..]).await,
+        Some("upgrade-room") => upgrade_room(&args[2..]).await,
+        Some("gc") => gc(&args[2..]).await,
+        Some("export") => export(&args[2..]).await,
+        Some("import") => import(&args[2..]).await,
+        Some("mirror") => mirror(&args[2..]).await,
+        Some("whoami") => whoami(args.get(2).map(|remote| &remote[..]).unwrap_or("origin")).await,
+        Some("logout") => logout(args.get(2).map(|remote| &remote[..]).unwrap_or("origin")).await,
+        Some("status") => status().await,
+        Some(_) => usage(),
+    }
+}
+
+fn usage() {
+    eprintln!("Usage: git matrix [login] [--remote <remote-name>] [--homeserver <url>] [--user <user-id>]");
+    eprintln!("                          [--password-stdin | --access-token-stdin | --sso] [--refresh-token]");
+    eprintln!("       git matrix init <#alias:server> [--public] [--push [<remote-name>]]");
+    eprintln!("                       [--description <text>] [--homepage <url>] [--license <id>]");
+    eprintln!("       git matrix whoami [<remote-name>]");
+    eprintln!("       git matrix logout [<remote-name>]");
+    eprintln!("       git matrix status");
+    eprintln!("       git matrix info [<remote-name>]");
+    eprintln!("       git matrix collaborators [--remote <remote-name>] list");
+    eprintln!("       git matrix collaborators [--remote <remote-name>] add <user-id> [<role>]");
+    eprintln!("       git matrix collaborators [--remote <remote-name>] remove <user-id>");
+    eprintln!("       git matrix collaborators [--remote <remote-name>] set-role <user-id> <role>");
+    eprintln!("       git matrix trusted-keys [--remote <remote-name>] list");
+    eprintln!(
+        "       git matrix trusted-keys [--remote <remote-name>] add <user-id> <format> <key>"
+    );
+    eprintln!("       git matrix trusted-keys [--remote <remote-name>] remove <user-id> <key>");
+    eprintln!("       git matrix trusted-keys [--remote <remote-name>] require | allow-unsigned");
+    eprintln!("       git matrix verify-push [--remote <remote-name>] <ref>");
+    eprintln!("       git matrix upgrade-room [--remote <remote-name>] <room-version>");
+    eprintln!("       git matrix gc [--remote <remote-name>] [--redact]");
+    eprintln!("       git matrix export <remote-name> <file.bundle>");
+    eprintln!("       git matrix import <file.bundle> <#alias:server> [--public]");
+    eprintln!("       git matrix mirror [--remote <remote-name>] --to <dir>");
+    eprintln!();
+    eprintln!("Roles are reader, writer and maintainer");
+    eprintln!("Key formats are openpgp with the key's fingerprint and ssh with the public key");
+    std::process::exit(1);
+}
+
+async fn login(args: &[String]) {
+    let mut remote = None;
+    let mut homeserver_url = std::env::var("GIT_MATRIX_HOMESERVER").ok();
+    let mut user = std::env::var("GIT_MATRIX_USER").ok();
+    let mut password = std::env::var("GIT_MATRIX_PASSWORD").ok();
+    let mut access_token = std::env::var("GIT_MATRIX_ACCESS_TOKEN").ok();
+    let mut sso = false;
+    let mut refresh_token = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--remote" => remote = args.next().cloned(),
+            "--homeserver" => homeserver_url = args.next().cloned(),
+            "--user" => user = args.next().cloned(),
+            "--password-stdin" => password = Some(read_stdin_line()),
+            "--access-token-stdin" => access_token = Some(read_stdin_line()),
+            "--sso" => sso = true,
+            "--refresh-token" => refresh_token = true,
+            _ => return usage(),
+        }
+    }
+
     let mut config = git::get_config().unwrap();
 
-    eprint!("Homeserver URL: ");
-    let homeserver_url: String = read!("{}\n");
-    eprint!("User: ");
-    let user: String = read!("{}\n");
-    let password = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-
-    let client = matrix::create_client(&homeserver_url, None).unwrap();
-    let session = client.log_in(user.clone(), password, None).await.unwrap();
-
-    config
-        .set_str("credential.matrix.url", &homeserver_url)
-        .unwrap();
-    config.set_str("credential.matrix.username", &user).unwrap();
-    config
-        .set_str("credential.matrix.access-token", &session.access_token)
-        .unwrap();
-    config
-        .set_str("credential.matrix.device-id", &session.device_id)
-        .unwrap();
+    let homeserver_url = match homeserver_url {
+        Some(homeserver_url) => homeserver_url,
+        None => {
+            eprint!("Homeserver URL: ");
+            read!("{}\n")
+        }
+    };
+    let homeserver_url = matrix::discover_homeserver(&homeserver_url).await.unwrap();
+
+    let credentials = match access_token {
+        Some(access_token) => {
+            let (user_id, device_id) = match matrix::whoami(&homeserver_url, &access_token).await {
+                Ok(whoami) => whoami,
+                Err(error) => exit_with(error),
+            };
+            Credentials {
+                homeserver_url,
+                user_id,
+                device_id: device_id.unwrap_or_default(),
+                access_token,
+                refresh_token: None,
+            }
+        }
+        None if sso || !supports_password_login(&homeserver_url).await => {
+            let token = sso_login_token(&homeserver_url);
+            match matrix::log_in_with_token(
+                &homeserver_url,
+                token,
+                DEVICE_DISPLAY_NAME,
+                refresh_token,
+            )
+            .await
+            {
+                Ok(credentials) => credentials,
+                Err(error) => exit_with(error),
+            }
+        }
+        None => {
+            let user = match user {
+                Some(user) => user,
+                None => {
+                    eprint!("User: ");
+                    read!("{}\n")
+                }
+            };
+            let password = match password {
+                Some(password) => password,
+                None => rpassword::read_password_from_tty(Some("Password: ")).unwrap(),
+            };
+
+            match matrix::log_in(
+                &homeserver_url,
+                user,
+                password,
+                DEVICE_DISPLAY_NAME,
+                refresh_token,
+            )
+            .await
+            {
+                Ok(credentials) => credentials,
+                Err(error) => exit_with(error),
+            }
+        }
+    };
+
+    // sessions used to be stored as a single credential.matrix section
+    for key in &["url", "username", "device-id", "access-token"] {
+        let _ = config.remove(&format!("credential.matrix.{}", key));
+    }
+
+    credentials.store(&mut config).unwrap();
+
+    if let Some(remote) = remote {
+        let git = git::Git::new().unwrap();
+        git.repo
+            .config()
+            .unwrap()
+            .set_str(
+                &format!("remote.{}.matrixUser", remote),
+                &credentials.user_id,
+            )
+            .unwrap();
+    }
 
     eprintln!("Logged in");
+    if config.get_string("credential.helper").is_err() {
+        eprintln!("No git credential helper is configured, the access token is not stored");
+        eprintln!("See https://git-scm.com/docs/gitcredentials");
+    }
+}
+
+async fn init(args: &[String]) {
+    let mut alias = None;
+    let mut push_remote = None;
+    let mut repo = RepoEventContent {
+        default_branch: git::Git::new().ok().and_then(|git| git.head_branch()),
+        visibility: Some("private".to_owned()),
+        ..RepoEventContent::default()
+    };
+
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--public" => repo.visibility = Some("public".to_owned()),
+            "--description" => repo.description = args.next().cloned(),
+            "--homepage" => repo.homepage = args.next().cloned(),
+            "--license" => repo.license = args.next().cloned(),
+            "--push" => {
+                let remote = match args.peek() {
+                    Some(remote) if !remote.starts_with("--") => args.next().unwrap().clone(),
+                    _ => "origin".to_owned(),
+                };
+                push_remote = Some(remote);
+            }
+            _ if alias.is_none() => alias = Some(arg.clone()),
+            _ => usage(),
+        }
+    }
+    let alias = match alias {
+        Some(alias) => alias,
+        None => return usage(),
+    };
+
+    let url = match alias_url(&alias) {
+        Some(url) => url,
+        None => return usage(),
+    };
+
+    // checked before creating the room, an existing remote is only reused if
+    // it is for the same room
+    let mut add_remote = false;
+    if let Some(remote) = &push_remote {
+        let git = git::Git::new().unwrap();
+        let existing_url = git
+            .repo
+            .find_remote(remote)
+            .ok()
+            .map(|existing| existing.url().unwrap_or_default().to_owned());
+        match existing_url {
+            Some(existing_url) if existing_url == url => {}
+            Some(existing_url) => {
+                eprintln!(
+                    "The remote {} already exists with URL {}, pass another remote name to --push",
+                    remote, existing_url
+                );
+                std::process::exit(1);
+            }
+            None => add_remote = true,
+        }
+    }
+
+    if let Err(error) = GitMatrixBuilder::new(url.clone()).create(repo).await {
+        exit_with(error);
+    }
+    eprintln!("Created {}", alias);
+
+    if let Some(remote) = push_remote {
+        if add_remote {
+            let git = git::Git::new().unwrap();
+            git.repo.remote(&remote, &url).unwrap();
+        }
+
+        let status = Command::new("git")
+            .args(["push", "--all", &remote])
+            .status()
+            .unwrap();
+        if !status.success() {
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Remote URL for a room alias, #repo:example.org -> matrix://example.org/repo
+fn alias_url(alias: &str) -> Option<String> {
+    match (alias.starts_with('#'), alias.find(':')) {
+        (true, Some(separator)) => Some(format!(
+            "matrix://{}/{}",
+            &alias[separator + 1..],
+            &alias[1..separator]
+        )),
+        _ => None,
+    }
+}
+
+async fn info(remote: &str) {
+    let git_matrix = build(remote).await;
+
+    let repo = match git_matrix.repo() {
+        Some(repo) => repo,
+        None => {
+            println!("The room has no repository metadata");
+            return;
+        }
+    };
+
+    let fields = [
+        ("Description", &repo.description),
+        ("Default branch", &repo.default_branch),
+        ("Visibility", &repo.visibility),
+        ("License", &repo.license),
+        ("Homepage", &repo.homepage),
+    ];
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    println!("Format version: {}", repo.version);
+}
+
+/// URL of a configured remote, without the `matrix::` transport prefix
+fn remote_url(remote: &str) -> String {
+    let git = open_repository();
+    let url = match git.repo.find_remote(remote) {
+        Ok(remote) => remote.url().map(|url| url.to_owned()),
+        Err(_) => exit_with(Error {
+            message: format!("No remote {}", remote),
+        }),
+    };
+    match url {
+        Some(url) => url.trim_start_matches("matrix::").to_owned(),
+        None => exit_with(Error {
+            message: format!("The URL of the remote {} is not valid UTF-8", remote),
+        }),
+    }
+}
+
+/// Repository of the working directory, exiting if there's none
+fn open_repository() -> git::Git {
+    match git::Git::new() {
+        Ok(git) => git,
+        Err(_) => exit_with(Error {
+            message: "Not in a git repository".to_owned(),
+        }),
+    }
+}
+
+async fn collaborators(args: &[String]) {
+    let (remote, args) = match args {
+        [flag, remote, args @ ..] if flag == "--remote" => (&remote[..], args),
+        args => ("origin", args),
+    };
+    let args: Vec<&str> = args.iter().map(|arg| &arg[..]).collect();
+
+    let git_matrix = build(remote).await;
+    let result = match &args[..] {
+        ["list"] => match git_matrix.collaborators().await {
+            Ok(collaborators) => {
+                for collaborator in collaborators {
+                    let invited = if collaborator.membership == "invite" {
+                        " (invited)"
+                    } else {
+                        ""
+                    };
+                    println!("{} {}{}", collaborator.user_id, collaborator.role, invited);
+                }
+                Ok(())
+            }
+            Err(error) => Err(error),
+        },
+        ["add", user_id] => git_matrix.add_collaborator(user_id, Role::Writer).await,
+        ["add", user_id, role] => match role.parse() {
+            Ok(role) => git_matrix.add_collaborator(user_id, role).await,
+            Err(error) => Err(error),
+        },
+        ["remove", user_id] => git_matrix.remove_collaborator(user_id).await,
+        ["set-role", user_id, role] => match role.parse() {
+            Ok(role) => git_matrix.set_role(user_id, role).await,
+            Err(error) => Err(error),
+        },
+        _ => return usage(),
+    };
+
+    if let Err(error) = result {
+        exit_with(error);
+    }
+}
+
+async fn trusted_keys(args: &[String]) {
+    let (remote, args) = match args {
+        [flag, remote, args @ ..] if flag == "--remote" => (&remote[..], args),
+        args => ("origin", args),
+    };
+    let args: Vec<&str> = args.iter().map(|arg| &arg[..]).collect();
+
+    let git_matrix = build(remote).await;
+    let mut trusted_keys = match git_matrix.trusted_keys().await {
+        Ok(trusted_keys) => trusted_keys,
+        Err(error) => exit_with(error),
+    };
+    match &args[..] {
+        ["list"] => {
+            for key in trusted_keys.keys {
+                println!("{} {} {}", key.user_id, key.format, key.key);
+            }
+            if trusted_keys.required {
+                println!("Unsigned ref updates are ignored");
+            }
+            return;
+        }
+        ["add", user_id, format, key] if *format == "openpgp" || *format == "ssh" => {
+            trusted_keys.keys.push(TrustedKey {
+                user_id: (*user_id).to_owned(),
+                format: (*format).to_owned(),
+                key: (*key).to_owned(),
+            })
+        }
+        ["remove", user_id, key] => trusted_keys
+            .keys
+            .retain(|trusted_key| trusted_key.user_id != *user_id || trusted_key.key != *key),
+        ["require"] => trusted_keys.required = true,
+        ["allow-unsigned"] => trusted_keys.required = false,
+        _ => return usage(),
+    }
+
+    if let Err(error) = git_matrix.set_trusted_keys(trusted_keys).await {
+        exit_with(error);
+    }
+}
+
+async fn verify_push(args: &[String]) {
+    let (remote, ref_name) = match args {
+        [flag, remote, ref_name] if flag == "--remote" => (&remote[..], ref_name),
+        [ref_name] => ("origin", ref_name),
+        _ => return usage(),
+    };
+    let ref_name = if ref_name.starts_with("refs/") {
+        ref_name.to_owned()
+    } else {
+        format!("refs/heads/{}", ref_name)
+    };
+
+    match build(remote).await.verify_push(&ref_name).await {
+        Ok(certificate) => {
+            print!("{}", certificate.content.certificate);
+            println!("Good signature from {}", certificate.sender);
+        }
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn upgrade_room(args: &[String]) {
+    let (remote, room_version) = match args {
+        [flag, remote, room_version] if flag == "--remote" => (&remote[..], room_version),
+        [room_version] => ("origin", room_version),
+        _ => return usage(),
+    };
+
+    match build(remote).await.upgrade_room(room_version).await {
+        Ok(room_id) => eprintln!("Upgraded the room, the repository is now in {}", room_id),
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn whoami(remote: &str) {
+    let (homeserver_url, credentials) = session(remote).await;
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            println!("Guest on {}", homeserver_url);
+            return;
+        }
+    };
+
+    match matrix::whoami(&credentials.homeserver_url, &credentials.access_token).await {
+        Ok((user_id, device_id)) => {
+            println!("User: {}", user_id);
+            if let Some(device_id) = device_id {
+                println!("Device: {}", device_id);
+            }
+            println!("Homeserver: {}", credentials.homeserver_url);
+        }
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn logout(remote: &str) {
+    let credentials = match session(remote).await {
+        (_, Some(credentials)) => credentials,
+        (homeserver_url, None) => {
+            eprintln!("Not logged in to {}", homeserver_url);
+            return;
+        }
+    };
+
+    // remove the stored session even if the server already forgot about it
+    if let Err(error) = matrix::log_out(
+        &credentials.homeserver_url,
+        &credentials.user_id,
+        credentials.access_token.clone(),
+        credentials.device_id.clone(),
+    )
+    .await
+    {
+        eprintln!("Invalidating the access token failed: {}", error.message);
+    }
+
+    let mut config = git::get_config().unwrap();
+    if let Err(error) = credentials.remove(&mut config) {
+        exit_with(error);
+    }
+
+    eprintln!("Logged out {}", credentials.user_id);
+}
+
+/// How each Matrix remote of the repository would be accessed
+async fn status() {
+    let git = open_repository();
+    let remotes = match git.repo.remotes() {
+        Ok(remotes) => remotes,
+        Err(error) => exit_with(error.into()),
+    };
+
+    for remote in remotes.iter().flatten() {
+        let is_matrix = match git.repo.find_remote(remote) {
+            Ok(remote) => remote.url().unwrap_or_default().starts_with("matrix:"),
+            Err(_) => false,
+        };
+        if !is_matrix {
+            continue;
+        }
+
+        match builder(remote).session().await {
+            Ok((homeserver_url, Some(credentials))) => {
+                println!("{}: {} on {}", remote, credentials.user_id, homeserver_url)
+            }
+            Ok((homeserver_url, None)) => println!("{}: guest on {}", remote, homeserver_url),
+            Err(error) => println!("{}: {}", remote, error.message),
+        }
+    }
+}
+
+async fn session(remote: &str) -> (String, Option<Credentials>) {
+    match builder(remote).session().await {
+        Ok(session) => session,
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn gc(args: &[String]) {
+    let mut remote = "origin";
+    let mut redact = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--remote" => match args.next() {
+                Some(name) => remote = name,
+                None => return usage(),
+            },
+            "--redact" => redact = true,
+            _ => return usage(),
+        }
+    }
+
+    match build(remote).await.gc(redact).await {
+        Ok(superseded) if redact => eprintln!("Consolidated and redacted {} packs", superseded),
+        Ok(superseded) => eprintln!("Consolidated {} packs", superseded),
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn export(args: &[String]) {
+    let (remote, path) = match args {
+        [remote, path] => (remote, path),
+        _ => return usage(),
+    };
+
+    let bundle = match build(remote).await.export_bundle().await {
+        Ok(bundle) => bundle,
+        Err(error) => exit_with(error),
+    };
+    std::fs::write(path, bundle.write()).unwrap();
+    eprintln!("Exported {} refs to {}", bundle.refs.len(), path);
+}
+
+async fn import(args: &[String]) {
+    let (path, alias, public) = match args {
+        [path, alias] => (path, alias, false),
+        [path, alias, flag] if flag == "--public" => (path, alias, true),
+        _ => return usage(),
+    };
+    let url = match alias_url(alias) {
+        Some(url) => url,
+        None => return usage(),
+    };
+
+    let bundle = match Bundle::read(&std::fs::read(path).unwrap()) {
+        Ok(bundle) => bundle,
+        Err(error) => exit_with(error),
+    };
+    let repo = RepoEventContent {
+        default_branch: bundle.head_branch.clone(),
+        visibility: Some(if public { "public" } else { "private" }.to_owned()),
+        ..RepoEventContent::default()
+    };
+
+    let git_matrix = match GitMatrixBuilder::new(url).create(repo).await {
+        Ok(git_matrix) => git_matrix,
+        Err(error) => exit_with(error),
+    };
+    let refs = bundle.refs.len();
+    match git_matrix.import_bundle(bundle).await {
+        Ok(skipped) => {
+            for ref_name in &skipped {
+                eprintln!("warning: skipped {}, it belongs to another user", ref_name);
+            }
+            eprintln!("Imported {} refs to {}", refs - skipped.len(), alias);
+        }
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn mirror(args: &[String]) {
+    let mut remote = "origin";
+    let mut dir = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (&arg[..], args.next()) {
+            ("--remote", Some(name)) => remote = name,
+            ("--to", Some(path)) => dir = Some(path),
+            _ => return usage(),
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return usage(),
+    };
+
+    match build(remote).await.mirror(std::path::Path::new(dir)).await {
+        Ok(downloaded) => eprintln!("Mirrored to {}, downloaded {} packs", dir, downloaded),
+        Err(error) => exit_with(error),
+    }
+}
+
+async fn build(remote: &str) -> GitMatrix {
+    match builder(remote).build().await {
+        Ok(git_matrix) => git_matrix,
+        Err(error) => exit_with(error),
+    }
+}
+
+fn builder(remote: &str) -> GitMatrixBuilder {
+    let mut builder = GitMatrixBuilder::new(remote_url(remote));
+    builder.remote_name(remote.to_owned());
+    builder
+}
+
+async fn supports_password_login(homeserver_url: &str) -> bool {
+    match matrix::login_types(homeserver_url).await {
+        Ok(login_types) => login_types
+            .iter()
+            .any(|login_type| login_type == "m.login.password"),
+        // let the password login report what's wrong
+        Err(_) => true,
+    }
+}
+
+/// Let the user log in through the browser and wait for the homeserver to redirect
+/// back to a listener on the loopback interface with the login token
+fn sso_login_token(homeserver_url: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let redirect_url = format!("http://{}/", listener.local_addr().unwrap());
+    let url = matrix::sso_redirect_url(homeserver_url, &redirect_url).unwrap();
+
+    eprintln!("Log in through your browser: {}", url);
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    let _ = Command::new(opener).arg(&url).status();
+
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut request_line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .unwrap();
+
+        // GET /?loginToken=<token> HTTP/1.1
+        let token = request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.split_once('?'))
+            .and_then(|(_, query)| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "loginToken")
+                    .map(|(_, token)| token.into_owned())
+            });
+
+        match token {
+            Some(token) => {
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n\
+                      Logged in to git-matrix, you can close this window.\n",
+                );
+                return token;
+            }
+            None => {
+                let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n");
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+fn read_stdin_line() -> String {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    line.trim_end_matches(&['\r', '\n'][..]).to_owned()
+}
+
+fn exit_with(error: Error) -> ! {
+    eprintln!("{}", error.message);
+    std::process::exit(1);
 }
@@ -1,5 +1,6 @@
 use std::io;
 
+use git_matrix::error::Error;
 use git_matrix::*;
 
 #[tokio::main]
@@ -11,11 +12,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     eprintln!("args: {:?}", args);
 
-    let _remote = args[1].clone();
+    let remote = args[1].clone();
     let url = args[2].clone();
 
-    let git_matrix = GitMatrixBuilder::new(url).build().await.unwrap();
+    match mirror::Mirror::open(&url) {
+        Ok(Some(mirror)) => return serve_mirror(mirror),
+        Ok(None) => (),
+        Err(error) => exit_with(error),
+    }
+
+    let mut builder = GitMatrixBuilder::new(url);
+    builder.remote_name(remote);
+    let mut git_matrix = match builder.build().await {
+        Ok(git_matrix) => git_matrix,
+        Err(error) => exit_with(error),
+    };
 
+    // fetch commands come in a batch ended by an empty line
+    let mut fetch_shas: Vec<String> = Vec::new();
     loop {
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
@@ -25,14 +39,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         if input == "capabilities" {
             println!("push");
             println!("fetch");
+            println!("option");
             println!("");
+        } else if input.starts_with("option") {
+            // git push --signed
+            match &input.split(' ').collect::<Vec<&str>>()[..] {
+                ["option", "pushcert", "true"] | ["option", "pushcert", "if-asked"] => {
+                    git_matrix.set_push_cert(true);
+                    println!("ok");
+                }
+                ["option", "pushcert", "false"] => {
+                    git_matrix.set_push_cert(false);
+                    println!("ok");
+                }
+                _ => println!("unsupported"),
+            }
         } else if input.starts_with("list") {
-            let refs = git_matrix.refs().await.unwrap();
+            let refs = match git_matrix.verified_refs().await {
+                Ok(refs) => refs,
+                Err(error) => exit_with(error),
+            };
             if refs.len() > 0 {
-                for (ref_name, ref_sha) in refs {
+                for (ref_name, (ref_sha, verification)) in refs {
+                    if let signing::Verification::Unverified(reason) = verification {
+                        eprintln!("warning: {} is not verified: {}", ref_name, reason);
+                    }
                     println!("{} {}", ref_sha, ref_name);
                 }
-                println!("@refs/heads/master HEAD");
+                let default_branch = git_matrix
+                    .repo()
+                    .and_then(|repo| repo.default_branch.clone())
+                    .unwrap_or_else(|| "master".to_owned());
+                println!("@refs/heads/{} HEAD", default_branch);
             }
             println!("");
         } else if input.starts_with("push") {
@@ -40,17 +78,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let refs: Vec<&str> = push_args[1].split(":").collect();
             let src = refs[0];
             let dst = refs[1];
-            git_matrix.push(src, dst).await.unwrap();
-            println!("ok {}", dst);
+            match git_matrix.push(src, dst).await {
+                Ok(()) => println!("ok {}", dst),
+                Err(error) => println!("error {} {}", dst, error.message),
+            }
 
             println!("");
         } else if input.starts_with("fetch") {
-            git_matrix.fetch().await.unwrap();
-            println!("");
+            // fetch <sha> <ref>
+            fetch_shas.push(input.split(' ').nth(1).unwrap_or_default().to_owned());
         } else if input == "" {
-            break;
+            if fetch_shas.is_empty() {
+                break;
+            }
+            if let Err(error) = git_matrix.fetch_commits(&fetch_shas).await {
+                exit_with(error);
+            }
+            fetch_shas.clear();
+            println!("");
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetch from a mirror directory made by git matrix mirror, which can't be pushed to
+fn serve_mirror(mirror: mirror::Mirror) -> Result<(), Box<dyn std::error::Error>> {
+    // fetch commands come in a batch ended by an empty line
+    let mut fetching = false;
+    loop {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        let input = input.trim();
+
+        if input == "capabilities" {
+            println!("fetch");
+            println!();
+        } else if input.starts_with("list") {
+            let refs = match mirror.verified_refs() {
+                Ok(refs) => refs,
+                Err(error) => exit_with(error),
+            };
+            if !refs.is_empty() {
+                for (ref_name, (ref_sha, _)) in refs {
+                    println!("{} {}", ref_sha, ref_name);
+                }
+                let default_branch = match mirror.repo() {
+                    Ok(repo) => repo,
+                    Err(error) => exit_with(error),
+                }
+                .and_then(|repo| repo.default_branch)
+                .unwrap_or_else(|| "master".to_owned());
+                println!("@refs/heads/{} HEAD", default_branch);
+            }
+            println!();
+        } else if input.starts_with("fetch") {
+            if !fetching {
+                if let Err(error) = mirror.fetch() {
+                    exit_with(error);
+                }
+                fetching = true;
+            }
+        } else if input.is_empty() {
+            if !fetching {
+                break;
+            }
+            fetching = false;
+            println!();
         }
     }
 
     Ok(())
 }
+
+fn exit_with(error: Error) -> ! {
+    eprintln!("{}", error.message);
+    std::process::exit(1);
+}